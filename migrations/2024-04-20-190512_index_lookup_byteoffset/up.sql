@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE INDEX lookup_byteoffset_idx ON lookup (byteoffset);
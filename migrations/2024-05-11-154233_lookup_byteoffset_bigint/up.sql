@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- byte offsets pass 2^31 on a full enwiki graph. A single node is never that long, so length stays an INTEGER.
+ALTER TABLE lookup ALTER COLUMN byteoffset TYPE BIGINT;
@@ -1,58 +1,392 @@
-use crate::utils::sanitize_string;
+use crate::error::WikigraphError;
+use crate::graph_builder::EdgeData;
 use std::{
     fs::{File, OpenOptions},
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    str::FromStr,
 };
+
+//One page of the adjacency list: the byte offset it got in the first pass and the titles it links to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdjacencyRecord {
+    pub offset: u64,
+    pub links: Vec<String>,
+    //The last `template_links` links come from templates.
+    pub template_links: usize,
+    //One per link for graphs with edge data (see GraphBuilder::has_edge_data), empty otherwise.
+    pub edge_data: Vec<EdgeData>,
+}
+
+pub type AdjacencyRecords<'a> =
+    Box<dyn Iterator<Item = Result<AdjacencyRecord, WikigraphError>> + 'a>;
+
 pub trait AdjacencyListHandler {
+    //The last `template_links` of `links` come from templates. `edge_data` is empty or has one entry per link.
+    fn add_to_adj_list(
+        &mut self,
+        offset: u64,
+        count: usize,
+        links: Vec<String>,
+        template_links: usize,
+        edge_data: Vec<EdgeData>,
+    ) -> Result<(), WikigraphError>;
+    //Every record written so far, from the top of the list.
+    fn iter(&self) -> Result<AdjacencyRecords<'_>, WikigraphError>;
+    //Size of what has been written so far, recorded in checkpoints.
+    fn position(&mut self) -> Result<u64, WikigraphError>;
+    //Drops everything after `len` bytes, new lines are appended from there.
+    fn truncate(&mut self, len: u64) -> Result<(), WikigraphError>;
+}
+
+//Lets a format picked at runtime (see `open`) be used wherever an AdjacencyListHandler is expected.
+impl<T: AdjacencyListHandler + ?Sized> AdjacencyListHandler for Box<T> {
     fn add_to_adj_list(
         &mut self,
-        title: &str,
+        offset: u64,
         count: usize,
         links: Vec<String>,
-    ) -> Result<(), diesel::result::Error>;
-    fn iter(&self) -> std::io::Lines<std::io::BufReader<&File>>;
+        template_links: usize,
+        edge_data: Vec<EdgeData>,
+    ) -> Result<(), WikigraphError> {
+        (**self).add_to_adj_list(offset, count, links, template_links, edge_data)
+    }
+    fn iter(&self) -> Result<AdjacencyRecords<'_>, WikigraphError> {
+        (**self).iter()
+    }
+    fn position(&mut self) -> Result<u64, WikigraphError> {
+        (**self).position()
+    }
+    fn truncate(&mut self, len: u64) -> Result<(), WikigraphError> {
+        (**self).truncate(len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdjacencyListFormat {
+    //`offset|count|link|link` lines, easy to grep but breaks on titles with a `|` or a newline.
+    Text,
+    //Length prefixed records, see BinaryAdjacencyListHandler.
+    Binary,
+}
+
+impl FromStr for AdjacencyListFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(AdjacencyListFormat::Text),
+            "binary" => Ok(AdjacencyListFormat::Binary),
+            _ => Err(format!(
+                "unknown adjacency list format {}, expected text or binary",
+                input
+            )),
+        }
+    }
+}
+
+pub fn open(
+    file_path: &str,
+    format: AdjacencyListFormat,
+) -> Result<Box<dyn AdjacencyListHandler>, WikigraphError> {
+    Ok(match format {
+        AdjacencyListFormat::Text => Box::new(WikigraphAdjacencyListHandler::new(file_path)?),
+        AdjacencyListFormat::Binary => Box::new(BinaryAdjacencyListHandler::new(file_path)?),
+    })
+}
+
+fn open_file(file_path: &str) -> Result<File, WikigraphError> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true) // This will create the file if it doesn't exist.
+        .truncate(false)
+        .open(file_path)?)
+}
+
+fn truncate_file(file: &mut File, len: u64) -> Result<(), WikigraphError> {
+    file.set_len(len)?;
+    file.seek(SeekFrom::Start(len))?;
+    Ok(())
+}
+
+//always read from the top, the cursor is at the end if we just finished writing the list
+fn read_from_start(file: &File) -> Result<BufReader<&File>, WikigraphError> {
+    let mut file = file;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(BufReader::new(file))
 }
+
 pub struct WikigraphAdjacencyListHandler {
     adj_list: File,
 }
 
 impl WikigraphAdjacencyListHandler {
-    pub fn new(file_path: &str) -> Self {
-        let adj_list = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true) // This will create the file if it doesn't exist.
-            .open(file_path)
-            .unwrap();
-        WikigraphAdjacencyListHandler { adj_list }
+    pub fn new(file_path: &str) -> Result<Self, WikigraphError> {
+        Ok(WikigraphAdjacencyListHandler {
+            adj_list: open_file(file_path)?,
+        })
     }
 }
 impl AdjacencyListHandler for WikigraphAdjacencyListHandler {
     fn add_to_adj_list(
         &mut self,
-        title_byte_offset: &str,
+        offset: u64,
         count: usize,
         links: Vec<String>,
-    ) -> Result<(), diesel::result::Error> {
-        let mut line = sanitize_string(title_byte_offset) + "|";
-        line.push_str(&count.to_string());
+        template_links: usize,
+        edge_data: Vec<EdgeData>,
+    ) -> Result<(), WikigraphError> {
+        let mut line = offset.to_string() + "|";
+        //`body+template` when some links come from templates, lines without any look like they always did.
+        if template_links > 0 {
+            line.push_str(&format!("{}+{}", count - template_links, template_links));
+        } else {
+            line.push_str(&count.to_string());
+        }
         line.push('|');
         for link in links.iter() {
             line.push_str(link);
             line.push('|');
         }
+        for edge_data in edge_data.iter() {
+            line.push_str(&edge_data.occurrences.to_string());
+            if edge_data.lead {
+                line.push(LEAD_SECTION_MARKER);
+            }
+            line.push('|');
+        }
         while line.ends_with('|') {
             line.pop(); //remove trailing '|'
         }
         line.push('\n');
-        let _ = match self.adj_list.write_all(line.as_bytes()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
-        };
+        self.adj_list.write_all(line.as_bytes())?;
+        Ok(())
+    }
+    fn iter(&self) -> Result<AdjacencyRecords<'_>, WikigraphError> {
+        let lines = read_from_start(&self.adj_list)?.lines();
+        Ok(Box::new(
+            lines
+                .enumerate()
+                .map(|(index, line)| parse_line(&line?, index + 1)),
+        ))
+    }
+    fn position(&mut self) -> Result<u64, WikigraphError> {
+        Ok(self.adj_list.metadata()?.len())
+    }
+    fn truncate(&mut self, len: u64) -> Result<(), WikigraphError> {
+        truncate_file(&mut self.adj_list, len)
+    }
+}
+
+//Follows the number of occurrences of a link in the lead section, ie: `3*`.
+const LEAD_SECTION_MARKER: char = '*';
+
+//Splits an `offset|num_links|link|link...` line, where num_links can be `body+template`. The links can be followed by
+//as many edge data fields, `occurrences` or `occurrences*`. `line_number` is only used in errors.
+fn parse_line(line: &str, line_number: usize) -> Result<AdjacencyRecord, WikigraphError> {
+    let mut split = line.split('|');
+    let offset = split
+        .next()
+        .and_then(|t| t.parse::<u64>().ok())
+        .ok_or_else(|| {
+            WikigraphError::Format(format!(
+                "adjacency list line {} does not start with a byte offset",
+                line_number
+            ))
+        })?;
+    let (num_links, template_links) = split
+        .next()
+        .and_then(|n| match n.split_once('+') {
+            Some((body, template)) => {
+                let template: usize = template.parse().ok()?;
+                Some((body.parse::<usize>().ok()? + template, template))
+            }
+            None => Some((n.parse().ok()?, 0)),
+        })
+        .ok_or_else(|| {
+            WikigraphError::Format(format!(
+                "adjacency list line {} has no link count",
+                line_number
+            ))
+        })?;
+    let fields: Vec<&str> = split.collect();
+    if fields.len() != num_links && fields.len() != 2 * num_links {
+        return Err(WikigraphError::Format(format!(
+            "adjacency list line {} says it has {} links but lists {}",
+            line_number,
+            num_links,
+            fields.len()
+        )));
+    }
+    let (link_fields, edge_data_fields) = fields.split_at(num_links);
+    let mut links = Vec::with_capacity(num_links);
+    for link in link_fields {
+        if link.is_empty() {
+            return Err(WikigraphError::Format(format!(
+                "empty link on adjacency list line {}",
+                line_number
+            )));
+        }
+        links.push((*link).to_owned());
+    }
+    let edge_data = edge_data_fields
+        .iter()
+        .map(|field| {
+            let (occurrences, lead) = match field.strip_suffix(LEAD_SECTION_MARKER) {
+                Some(occurrences) => (occurrences, true),
+                None => (*field, false),
+            };
+            let occurrences = occurrences.parse().map_err(|_| {
+                WikigraphError::Format(format!(
+                    "adjacency list line {} has edge data \"{}\" that is not a number of occurrences",
+                    line_number, field
+                ))
+            })?;
+            Ok(EdgeData { occurrences, lead })
+        })
+        .collect::<Result<Vec<_>, WikigraphError>>()?;
+    Ok(AdjacencyRecord {
+        offset,
+        links,
+        template_links,
+        edge_data,
+    })
+}
+
+//Each record is the byte offset, the number of links, how many of them come from templates and whether they have edge
+//data (0 or 1) as LEB128 varints, then every link as a varint byte length followed by the UTF-8 title, then the
+//occurrences and flags of every link as varints if it has edge data. Any title can be stored and nothing has to be
+//split or searched.
+pub struct BinaryAdjacencyListHandler {
+    adj_list: File,
+}
 
+impl BinaryAdjacencyListHandler {
+    pub fn new(file_path: &str) -> Result<Self, WikigraphError> {
+        Ok(BinaryAdjacencyListHandler {
+            adj_list: open_file(file_path)?,
+        })
+    }
+}
+
+impl AdjacencyListHandler for BinaryAdjacencyListHandler {
+    fn add_to_adj_list(
+        &mut self,
+        offset: u64,
+        count: usize,
+        links: Vec<String>,
+        template_links: usize,
+        edge_data: Vec<EdgeData>,
+    ) -> Result<(), WikigraphError> {
+        //one write per record like the text format, a crash does not leave half a record before a checkpoint.
+        let mut record = Vec::new();
+        write_varint(&mut record, offset);
+        write_varint(&mut record, count as u64);
+        write_varint(&mut record, template_links as u64);
+        write_varint(&mut record, u64::from(!edge_data.is_empty()));
+        for link in links.iter() {
+            write_varint(&mut record, link.len() as u64);
+            record.extend_from_slice(link.as_bytes());
+        }
+        for edge_data in edge_data.iter() {
+            write_varint(&mut record, u64::from(edge_data.occurrences));
+            write_varint(&mut record, u64::from(edge_data.flags()));
+        }
+        self.adj_list.write_all(&record)?;
         Ok(())
     }
-    fn iter(&self) -> std::io::Lines<std::io::BufReader<&File>> {
-        BufReader::new(&self.adj_list).lines()
+    fn iter(&self) -> Result<AdjacencyRecords<'_>, WikigraphError> {
+        let mut reader = read_from_start(&self.adj_list)?;
+        let mut index = 0;
+        Ok(Box::new(std::iter::from_fn(move || {
+            index += 1;
+            read_record(&mut reader, index).transpose()
+        })))
+    }
+    fn position(&mut self) -> Result<u64, WikigraphError> {
+        Ok(self.adj_list.metadata()?.len())
+    }
+    fn truncate(&mut self, len: u64) -> Result<(), WikigraphError> {
+        truncate_file(&mut self.adj_list, len)
+    }
+}
+
+//Returns None at the end of the list. `index` is only used in errors.
+fn read_record<R: BufRead>(
+    reader: &mut R,
+    index: usize,
+) -> Result<Option<AdjacencyRecord>, WikigraphError> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let truncated = |e: std::io::Error| match e.kind() {
+        ErrorKind::UnexpectedEof => {
+            WikigraphError::Format(format!("adjacency list record {} is cut off", index))
+        }
+        _ => e.into(),
+    };
+    let offset = read_varint(reader).map_err(truncated)?;
+    let num_links = read_varint(reader).map_err(truncated)?;
+    let template_links = read_varint(reader).map_err(truncated)? as usize;
+    let has_edge_data = read_varint(reader).map_err(truncated)? != 0;
+    let mut links = Vec::new();
+    for _ in 0..num_links {
+        let len = read_varint(reader).map_err(truncated)?;
+        let mut bytes = Vec::new();
+        reader.by_ref().take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(truncated(ErrorKind::UnexpectedEof.into()));
+        }
+        let link = String::from_utf8(bytes).map_err(|_| {
+            WikigraphError::Format(format!(
+                "adjacency list record {} has a link that is not valid UTF-8",
+                index
+            ))
+        })?;
+        links.push(link);
+    }
+    let mut edge_data = Vec::new();
+    if has_edge_data {
+        for _ in 0..num_links {
+            let occurrences = read_varint(reader).map_err(truncated)?;
+            let flags = read_varint(reader).map_err(truncated)?;
+            edge_data.push(EdgeData::from_flags(occurrences as u32, flags as u32));
+        }
+    }
+    if template_links > links.len() {
+        return Err(WikigraphError::Format(format!(
+            "adjacency list record {} has more template links than links",
+            index
+        )));
+    }
+    Ok(Some(AdjacencyRecord {
+        offset,
+        links,
+        template_links,
+        edge_data,
+    }))
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut value = 0;
+    let mut byte = [0u8];
+    for shift in (0..64).step_by(7) {
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
     }
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        "varint longer than 64 bits",
+    ))
 }
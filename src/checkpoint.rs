@@ -0,0 +1,54 @@
+use crate::error::WikigraphError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+//State of the first pass right after a flush, when the database and the adjacency list agree with each other.
+//Resuming from it means: skip the dump up to `position`, cut the adjacency list (and link metadata) back,
+//drop lookup rows at or after the next byte offset and carry on with the same offsets and count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    //Byte position in the decompressed xml, or for multistream dumps the offset of the next stream to process.
+    pub position: u64,
+    pub multistream: bool,
+    pub prev_offset: usize,
+    pub prev_length: usize,
+    pub count: i32,
+    pub adj_list_len: u64,
+    //Size of the link metadata sidecar, None if the run did not write one.
+    #[serde(default)]
+    pub link_metadata_len: Option<u64>,
+    //Links left out so far, see RemovedLinks.
+    #[serde(default)]
+    pub duplicate_links: u64,
+    #[serde(default)]
+    pub self_links: u64,
+}
+
+impl Checkpoint {
+    //Byte offset the next node will get, everything from here on was not part of the checkpoint.
+    pub fn next_offset(&self) -> usize {
+        self.prev_offset + self.prev_length
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, WikigraphError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            WikigraphError::Format(format!("invalid checkpoint {}: {}", path.display(), e))
+        })
+    }
+
+    //Written next to the target and renamed over it, a crash while checkpointing leaves the previous one intact.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), WikigraphError> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let contents = serde_json::to_string(self).map_err(|e| {
+            WikigraphError::Format(format!("could not serialize checkpoint: {}", e))
+        })?;
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
@@ -1,29 +1,142 @@
+use crate::error::WikigraphError;
 use crate::models::{LookupEntry, RedirectEntry};
 use crate::schema::lookup::dsl::*;
 use crate::schema::redirect::dsl::*;
+use diesel::connection::SimpleConnection;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
-use diesel::sql_types::Text;
-use diesel::{insert_into, sql_query};
+use diesel::sqlite::SqliteConnection;
+use diesel::{delete, insert_into, insert_or_ignore_into};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+//Rows per INSERT statement, keeps us under the bind parameter limits (65535 for postgres, 32766 for sqlite).
+const INSERT_CHUNK_SIZE: usize = 5000;
 
 pub trait DatabaseHandler {
-    fn lookup_with_redirects(
+    fn lookup_with_redirects(&mut self, input_title: &str) -> Result<LookupEntry, WikigraphError>;
+    fn add_lookup_entry(&mut self, lookup_entry: &LookupEntry) -> Result<(), WikigraphError>;
+    fn add_redirect_entry(&mut self, redirect_entry: &RedirectEntry) -> Result<(), WikigraphError>;
+    fn look_up_lookup_entry(&mut self, input_title: &str) -> Result<LookupEntry, WikigraphError>;
+    fn look_up_offset(&mut self, offset: i64) -> Result<LookupEntry, WikigraphError>;
+
+    fn read_offsets_into_memory(&mut self) -> Result<Vec<(String, i64)>, WikigraphError>;
+    //Every (redirect_from, redirect_to) pair, for resolving links to redirects while building the graph.
+    fn read_redirects_into_memory(&mut self) -> Result<Vec<(String, String)>, WikigraphError>;
+    //Removes the lookup entries at or after `offset`, used to roll back to a checkpoint. Returns how many were removed.
+    fn delete_lookup_entries_from(&mut self, offset: i64) -> Result<usize, WikigraphError>;
+    //Swaps the whole lookup table for `lookup_entries` in one transaction, a failed run leaves the old table in place.
+    fn replace_lookup_entries(
+        &mut self,
+        lookup_entries: &[LookupEntry],
+    ) -> Result<(), WikigraphError>;
+
+    //Batched versions of the methods above, the first pass inserts millions of rows.
+    //The defaults fall back to one query per row, backends override them with multi-row statements.
+
+    //Returns which of `titles` already have a lookup entry.
+    fn existing_titles(&mut self, titles: &[String]) -> Result<HashSet<String>, WikigraphError> {
+        let mut existing = HashSet::new();
+        for input_title in titles {
+            match self.look_up_lookup_entry(input_title) {
+                Ok(entry) => {
+                    existing.insert(entry.title);
+                }
+                Err(WikigraphError::Database(diesel::result::Error::NotFound)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(existing)
+    }
+    //Unlike add_redirect_entries, a duplicate title is an error. Callers filter them with existing_titles first.
+    fn add_lookup_entries(&mut self, lookup_entries: &[LookupEntry]) -> Result<(), WikigraphError> {
+        lookup_entries
+            .iter()
+            .try_for_each(|lookup_entry| self.add_lookup_entry(lookup_entry))
+    }
+    //Redirects that already exist are skipped.
+    fn add_redirect_entries(
         &mut self,
-        input_title: &str,
-    ) -> Result<LookupEntry, diesel::result::Error>;
-    fn add_lookup_entry(&mut self, lookup_entry: &LookupEntry)
-        -> Result<(), diesel::result::Error>;
-    fn add_redirect_entry(
+        redirect_entries: &[RedirectEntry],
+    ) -> Result<(), WikigraphError> {
+        redirect_entries
+            .iter()
+            .try_for_each(|redirect_entry| self.add_redirect_entry(redirect_entry))
+    }
+}
+
+//Lets a backend picked at runtime (see `connect`) be used wherever a DatabaseHandler is expected.
+impl<T: DatabaseHandler + ?Sized> DatabaseHandler for Box<T> {
+    fn lookup_with_redirects(&mut self, input_title: &str) -> Result<LookupEntry, WikigraphError> {
+        (**self).lookup_with_redirects(input_title)
+    }
+    fn add_lookup_entry(&mut self, lookup_entry: &LookupEntry) -> Result<(), WikigraphError> {
+        (**self).add_lookup_entry(lookup_entry)
+    }
+    fn add_redirect_entry(&mut self, redirect_entry: &RedirectEntry) -> Result<(), WikigraphError> {
+        (**self).add_redirect_entry(redirect_entry)
+    }
+    fn look_up_lookup_entry(&mut self, input_title: &str) -> Result<LookupEntry, WikigraphError> {
+        (**self).look_up_lookup_entry(input_title)
+    }
+    fn look_up_offset(&mut self, offset: i64) -> Result<LookupEntry, WikigraphError> {
+        (**self).look_up_offset(offset)
+    }
+    fn read_offsets_into_memory(&mut self) -> Result<Vec<(String, i64)>, WikigraphError> {
+        (**self).read_offsets_into_memory()
+    }
+    fn read_redirects_into_memory(&mut self) -> Result<Vec<(String, String)>, WikigraphError> {
+        (**self).read_redirects_into_memory()
+    }
+    fn delete_lookup_entries_from(&mut self, offset: i64) -> Result<usize, WikigraphError> {
+        (**self).delete_lookup_entries_from(offset)
+    }
+    fn replace_lookup_entries(
         &mut self,
-        redirect_entry: &RedirectEntry,
-    ) -> Result<(), diesel::result::Error>;
-    fn look_up_lookup_entry(
+        lookup_entries: &[LookupEntry],
+    ) -> Result<(), WikigraphError> {
+        (**self).replace_lookup_entries(lookup_entries)
+    }
+    fn existing_titles(&mut self, titles: &[String]) -> Result<HashSet<String>, WikigraphError> {
+        (**self).existing_titles(titles)
+    }
+    fn add_lookup_entries(&mut self, lookup_entries: &[LookupEntry]) -> Result<(), WikigraphError> {
+        (**self).add_lookup_entries(lookup_entries)
+    }
+    fn add_redirect_entries(
         &mut self,
-        input_title: &str,
-    ) -> Result<LookupEntry, diesel::result::Error>;
+        redirect_entries: &[RedirectEntry],
+    ) -> Result<(), WikigraphError> {
+        (**self).add_redirect_entries(redirect_entries)
+    }
+}
 
-    fn read_offsets_into_memory(&mut self) -> Vec<(String, i32)>;
+//Picks the backend from the database url:
+//  postgres://...  -> PostgresDatabaseHandler
+//  sqlite://path, or a path ending in .db/.sqlite/.sqlite3 -> SqliteDatabaseHandler
+//  memory: -> InMemoryDatabaseHandler (nothing is persisted, only useful for `all` or tests)
+pub fn connect(database_url: &str) -> Result<Box<dyn DatabaseHandler>, WikigraphError> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        return Ok(Box::new(PostgresDatabaseHandler::new(database_url)?));
+    }
+    if database_url == "memory:" {
+        return Ok(Box::new(InMemoryDatabaseHandler::new()));
+    }
+    if let Some(path) = database_url.strip_prefix("sqlite://") {
+        return Ok(Box::new(SqliteDatabaseHandler::new(path)?));
+    }
+    if [".db", ".sqlite", ".sqlite3"]
+        .iter()
+        .any(|extension| database_url.ends_with(extension))
+    {
+        return Ok(Box::new(SqliteDatabaseHandler::new(database_url)?));
+    }
+    Err(WikigraphError::Connection(
+        ConnectionError::InvalidConnectionUrl(format!(
+            "unsupported database url {}, expected postgres://, sqlite:// or memory:",
+            database_url
+        )),
+    ))
 }
 
 pub struct PostgresDatabaseHandler {
@@ -35,72 +148,292 @@ impl PostgresDatabaseHandler {
         let connection = PgConnection::establish(connection_string)?;
         Ok(PostgresDatabaseHandler { connection })
     }
+
+    fn insert_redirects_ignoring_duplicates(
+        connection: &mut PgConnection,
+        redirect_entries: &[RedirectEntry],
+    ) -> QueryResult<usize> {
+        insert_into(redirect)
+            .values(redirect_entries)
+            .on_conflict_do_nothing()
+            .execute(connection)
+    }
 }
 
-impl DatabaseHandler for PostgresDatabaseHandler {
-    fn lookup_with_redirects(
-        &mut self,
-        input_title: &str,
-    ) -> Result<LookupEntry, diesel::result::Error> {
-        let result = redirect
-            .filter(redirect_from.eq(&input_title))
-            .inner_join(lookup.on(redirect_to.eq(title)))
-            .select(lookup::all_columns())
-            .first::<LookupEntry>(&mut self.connection)
-            .optional()?;
-
-        match result {
-            Some(entry) => {
-                // println!(" {:?}", entry);
-                Ok(entry)
+//Embedded database so small wikis can be processed without a database server.
+pub struct SqliteDatabaseHandler {
+    connection: SqliteConnection,
+}
+
+impl SqliteDatabaseHandler {
+    pub fn new(path: &str) -> Result<Self, ConnectionError> {
+        let mut connection = SqliteConnection::establish(path)?;
+        //diesel migrations are written for postgres, so the sqlite schema is created here.
+        //synchronous = OFF: a crashed run has to be redone anyways, no point in paying for a fsync per insert.
+        connection
+            .batch_execute(
+                "PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = OFF;
+                CREATE TABLE IF NOT EXISTS lookup (
+                    title VARCHAR(255) NOT NULL PRIMARY KEY,
+                    byteoffset BIGINT NOT NULL,
+                    length INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS lookup_byteoffset_idx ON lookup (byteoffset);
+                CREATE TABLE IF NOT EXISTS redirect (
+                    redirect_from VARCHAR(255) NOT NULL PRIMARY KEY,
+                    redirect_to VARCHAR(255) NOT NULL
+                );",
+            )
+            .map_err(|e| ConnectionError::BadConnection(e.to_string()))?;
+        Ok(SqliteDatabaseHandler { connection })
+    }
+
+    //diesel does not support ON CONFLICT for multi-row inserts on sqlite, INSERT OR IGNORE does the same thing here.
+    fn insert_redirects_ignoring_duplicates(
+        connection: &mut SqliteConnection,
+        redirect_entries: &[RedirectEntry],
+    ) -> QueryResult<usize> {
+        insert_or_ignore_into(redirect)
+            .values(redirect_entries)
+            .execute(connection)
+    }
+}
+
+//Both diesel backends run the exact same queries.
+macro_rules! impl_diesel_database_handler {
+    ($handler:ty) => {
+        impl DatabaseHandler for $handler {
+            fn lookup_with_redirects(
+                &mut self,
+                input_title: &str,
+            ) -> Result<LookupEntry, WikigraphError> {
+                let result = redirect
+                    .filter(redirect_from.eq(&input_title))
+                    .inner_join(lookup.on(redirect_to.eq(title)))
+                    .select(lookup::all_columns())
+                    .first::<LookupEntry>(&mut self.connection)
+                    .optional()?;
+
+                match result {
+                    Some(entry) => {
+                        // println!(" {:?}", entry);
+                        Ok(entry)
+                    }
+                    None => {
+                        // println!("No redirect entry found for {}", &input_title);
+                        // Fallback to directly querying the lookups table if no entry was found through redirects
+                        Ok(lookup
+                            .filter(title.eq(input_title))
+                            .first::<LookupEntry>(&mut self.connection)?)
+                        // self.look_up_lookup_entry(&input_title)
+                    }
+                }
             }
-            None => {
-                // println!("No redirect entry found for {}", &input_title);
-                // Fallback to directly querying the lookups table if no entry was found through redirects
-                lookup
+            fn look_up_lookup_entry(
+                &mut self,
+                input_title: &str,
+            ) -> Result<LookupEntry, WikigraphError> {
+                Ok(lookup
                     .filter(title.eq(input_title))
-                    .first::<LookupEntry>(&mut self.connection)
-                // self.look_up_lookup_entry(&input_title)
+                    .first::<LookupEntry>(&mut self.connection)?)
+            }
+            fn look_up_offset(&mut self, offset: i64) -> Result<LookupEntry, WikigraphError> {
+                Ok(lookup
+                    .filter(byteoffset.eq(offset))
+                    .first::<LookupEntry>(&mut self.connection)?)
+            }
+
+            fn add_lookup_entry(
+                &mut self,
+                lookup_entry: &LookupEntry,
+            ) -> Result<(), WikigraphError> {
+                insert_into(lookup)
+                    .values(lookup_entry)
+                    .execute(&mut self.connection)?;
+                Ok(())
+            }
+            fn add_redirect_entry(
+                &mut self,
+                redirect_entry: &RedirectEntry,
+            ) -> Result<(), WikigraphError> {
+                match insert_into(redirect)
+                    .values(redirect_entry)
+                    .execute(&mut self.connection)
+                {
+                    Ok(_) => Ok(()),
+                    Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        Ok(()) //keep going if we encounter a duplicate key error.
+                    }
+                    Err(e) => Err(e.into()), // For other errors, we will propgate
+                }
+            }
+            fn read_offsets_into_memory(&mut self) -> Result<Vec<(String, i64)>, WikigraphError> {
+                Ok(lookup
+                    .select((title, byteoffset))
+                    .load(&mut self.connection)?)
+            }
+            fn read_redirects_into_memory(
+                &mut self,
+            ) -> Result<Vec<(String, String)>, WikigraphError> {
+                Ok(redirect
+                    .select((redirect_from, redirect_to))
+                    .load(&mut self.connection)?)
+            }
+            fn delete_lookup_entries_from(&mut self, offset: i64) -> Result<usize, WikigraphError> {
+                Ok(delete(lookup.filter(byteoffset.ge(offset))).execute(&mut self.connection)?)
+            }
+            fn replace_lookup_entries(
+                &mut self,
+                lookup_entries: &[LookupEntry],
+            ) -> Result<(), WikigraphError> {
+                self.connection
+                    .transaction(|connection| -> QueryResult<()> {
+                        delete(lookup).execute(connection)?;
+                        for chunk in lookup_entries.chunks(INSERT_CHUNK_SIZE) {
+                            insert_into(lookup).values(chunk).execute(connection)?;
+                        }
+                        Ok(())
+                    })?;
+                Ok(())
+            }
+            fn existing_titles(
+                &mut self,
+                titles: &[String],
+            ) -> Result<HashSet<String>, WikigraphError> {
+                let mut existing = HashSet::new();
+                for chunk in titles.chunks(INSERT_CHUNK_SIZE) {
+                    existing.extend(
+                        lookup
+                            .filter(title.eq_any(chunk))
+                            .select(title)
+                            .load::<String>(&mut self.connection)?,
+                    );
+                }
+                Ok(existing)
+            }
+            fn add_lookup_entries(
+                &mut self,
+                lookup_entries: &[LookupEntry],
+            ) -> Result<(), WikigraphError> {
+                self.connection
+                    .transaction(|connection| -> QueryResult<()> {
+                        for chunk in lookup_entries.chunks(INSERT_CHUNK_SIZE) {
+                            insert_into(lookup).values(chunk).execute(connection)?;
+                        }
+                        Ok(())
+                    })?;
+                Ok(())
+            }
+            fn add_redirect_entries(
+                &mut self,
+                redirect_entries: &[RedirectEntry],
+            ) -> Result<(), WikigraphError> {
+                self.connection
+                    .transaction(|connection| -> QueryResult<()> {
+                        for chunk in redirect_entries.chunks(INSERT_CHUNK_SIZE) {
+                            //keep going if we encounter a duplicate key error.
+                            <$handler>::insert_redirects_ignoring_duplicates(connection, chunk)?;
+                        }
+                        Ok(())
+                    })?;
+                Ok(())
             }
         }
+    };
+}
+
+impl_diesel_database_handler!(PostgresDatabaseHandler);
+impl_diesel_database_handler!(SqliteDatabaseHandler);
+
+//Keeps both tables in HashMaps, for tests and runs where nothing needs to outlive the process.
+#[derive(Default)]
+pub struct InMemoryDatabaseHandler {
+    lookup: HashMap<String, LookupEntry>,
+    offsets: BTreeMap<i64, String>,
+    redirect: HashMap<String, String>,
+}
+
+impl InMemoryDatabaseHandler {
+    pub fn new() -> Self {
+        InMemoryDatabaseHandler::default()
     }
-    fn look_up_lookup_entry(
-        &mut self,
-        input_title: &str,
-    ) -> Result<LookupEntry, diesel::result::Error> {
-        lookup
-            .filter(title.eq(input_title))
-            .first::<LookupEntry>(&mut self.connection)
+}
+
+impl DatabaseHandler for InMemoryDatabaseHandler {
+    fn lookup_with_redirects(&mut self, input_title: &str) -> Result<LookupEntry, WikigraphError> {
+        // Fallback to directly querying the lookups table if no entry was found through redirects
+        self.redirect
+            .get(input_title)
+            .and_then(|redirect_target| self.lookup.get(redirect_target))
+            .or_else(|| self.lookup.get(input_title))
+            .cloned()
+            .ok_or(WikigraphError::Database(diesel::result::Error::NotFound))
+    }
+    fn look_up_lookup_entry(&mut self, input_title: &str) -> Result<LookupEntry, WikigraphError> {
+        self.lookup
+            .get(input_title)
+            .cloned()
+            .ok_or(WikigraphError::Database(diesel::result::Error::NotFound))
+    }
+    fn look_up_offset(&mut self, offset: i64) -> Result<LookupEntry, WikigraphError> {
+        let entry_title = self
+            .offsets
+            .get(&offset)
+            .ok_or(WikigraphError::Database(diesel::result::Error::NotFound))?;
+        self.look_up_lookup_entry(&entry_title.clone())
     }
 
-    fn add_lookup_entry(
-        &mut self,
-        lookup_entry: &LookupEntry,
-    ) -> Result<(), diesel::result::Error> {
-        insert_into(lookup)
-            .values(lookup_entry)
-            .execute(&mut self.connection)
-            .map(|_| ())
-    }
-    fn add_redirect_entry(
-        &mut self,
-        redirect_entry: &RedirectEntry,
-    ) -> Result<(), diesel::result::Error> {
-        match insert_into(redirect)
-            .values(redirect_entry)
-            .execute(&mut self.connection)
-        {
-            Ok(_) => Ok(()),
-            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-                Ok(()) //keep going if we encounter a duplicate key error.
-            }
-            Err(e) => Err(e), // For other errors, we will propgate
+    fn add_lookup_entry(&mut self, lookup_entry: &LookupEntry) -> Result<(), WikigraphError> {
+        //same error as a primary key violation in the sql backends, the parser relies on it to skip duplicates
+        if self.lookup.contains_key(&lookup_entry.title) {
+            return Err(WikigraphError::Database(DatabaseError(
+                DatabaseErrorKind::UniqueViolation,
+                Box::new(format!("duplicate title {}", lookup_entry.title)),
+            )));
         }
+        self.offsets
+            .insert(lookup_entry.byteoffset, lookup_entry.title.clone());
+        self.lookup
+            .insert(lookup_entry.title.clone(), lookup_entry.clone());
+        Ok(())
+    }
+    fn add_redirect_entry(&mut self, redirect_entry: &RedirectEntry) -> Result<(), WikigraphError> {
+        //keep going if we encounter a duplicate key error.
+        self.redirect
+            .entry(redirect_entry.redirect_from.clone())
+            .or_insert_with(|| redirect_entry.redirect_to.clone());
+        Ok(())
+    }
+    fn read_offsets_into_memory(&mut self) -> Result<Vec<(String, i64)>, WikigraphError> {
+        Ok(self
+            .lookup
+            .values()
+            .map(|entry| (entry.title.clone(), entry.byteoffset))
+            .collect())
     }
-    fn read_offsets_into_memory(&mut self) -> Vec<(String, i32)> {
-        lookup
-            .select((title, byteoffset))
-            .load(&mut self.connection)
-            .unwrap()
+    fn read_redirects_into_memory(&mut self) -> Result<Vec<(String, String)>, WikigraphError> {
+        Ok(self
+            .redirect
+            .iter()
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .collect())
+    }
+    fn delete_lookup_entries_from(&mut self, offset: i64) -> Result<usize, WikigraphError> {
+        let removed = self.offsets.split_off(&offset);
+        for entry_title in removed.values() {
+            self.lookup.remove(entry_title);
+        }
+        Ok(removed.len())
+    }
+    fn replace_lookup_entries(
+        &mut self,
+        lookup_entries: &[LookupEntry],
+    ) -> Result<(), WikigraphError> {
+        self.lookup.clear();
+        self.offsets.clear();
+        lookup_entries
+            .iter()
+            .try_for_each(|lookup_entry| self.add_lookup_entry(lookup_entry))
     }
 }
@@ -0,0 +1,102 @@
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const READ_BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Bzip2,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    //Magic bytes win over the extension, so a renamed dump is still read correctly.
+    pub fn detect(path: &Path, magic: &[u8]) -> Compression {
+        if magic.starts_with(b"BZh") {
+            return Compression::Bzip2;
+        }
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            return Compression::Gzip;
+        }
+        if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            return Compression::Zstd;
+        }
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("bz2") => Compression::Bzip2,
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+//How much of the dump file has been read, before decompression. Lets progress be shown against the file size.
+#[derive(Debug, Clone)]
+pub struct InputProgress {
+    read: Arc<AtomicU64>,
+    total: u64,
+}
+
+impl InputProgress {
+    pub fn position(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+//Opens a wikipedia dump, decompressing .bz2/.gz/.zst on the fly.
+//Multistream dumps are several compressed streams back to back, the decoders used here read all of them.
+pub fn open_dump<P: AsRef<Path>>(path: P) -> std::io::Result<Box<dyn BufRead>> {
+    Ok(open_dump_with_progress(path)?.0)
+}
+
+//open_dump, also returning a handle on how far into the file reading got.
+pub fn open_dump_with_progress<P: AsRef<Path>>(
+    path: P,
+) -> std::io::Result<(Box<dyn BufRead>, InputProgress)> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let progress = InputProgress {
+        read: Arc::new(AtomicU64::new(0)),
+        total: file.metadata()?.len(),
+    };
+    let file = CountingReader {
+        inner: file,
+        read: progress.read.clone(),
+    };
+    let mut file = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+    let compression = Compression::detect(path, file.fill_buf()?);
+    let decoder: Box<dyn Read> = match compression {
+        Compression::None => return Ok((Box::new(file), progress)),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(file)),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+    };
+    Ok((
+        Box::new(BufReader::with_capacity(READ_BUFFER_SIZE, decoder)),
+        progress,
+    ))
+}
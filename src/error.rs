@@ -0,0 +1,63 @@
+use std::fmt;
+
+//Every fallible step of the pipeline ends up here, so a run that fails after hours says what went wrong and where.
+#[derive(Debug)]
+pub enum WikigraphError {
+    //The dump could not be parsed. `position` is the byte position in the (decompressed) xml.
+    Xml {
+        position: u64,
+        source: quick_xml::Error,
+    },
+    Io(std::io::Error),
+    Database(diesel::result::Error),
+    Connection(diesel::ConnectionError),
+    //An input or intermediate file (graph, adjacency list, multistream index) is not in the expected format.
+    Format(String),
+    //The files and the database disagree with each other, ie: the adjacency list does not match the lookup table.
+    Consistency(String),
+}
+
+impl fmt::Display for WikigraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WikigraphError::Xml { position, source } => {
+                write!(f, "xml error at byte {} of the dump: {}", position, source)
+            }
+            WikigraphError::Io(e) => write!(f, "io error: {}", e),
+            WikigraphError::Database(e) => write!(f, "database error: {}", e),
+            WikigraphError::Connection(e) => write!(f, "could not connect to the database: {}", e),
+            WikigraphError::Format(message) => write!(f, "invalid format: {}", message),
+            WikigraphError::Consistency(message) => write!(f, "inconsistent data: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for WikigraphError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WikigraphError::Xml { source, .. } => Some(source),
+            WikigraphError::Io(e) => Some(e),
+            WikigraphError::Database(e) => Some(e),
+            WikigraphError::Connection(e) => Some(e),
+            WikigraphError::Format(_) | WikigraphError::Consistency(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WikigraphError {
+    fn from(e: std::io::Error) -> Self {
+        WikigraphError::Io(e)
+    }
+}
+
+impl From<diesel::result::Error> for WikigraphError {
+    fn from(e: diesel::result::Error) -> Self {
+        WikigraphError::Database(e)
+    }
+}
+
+impl From<diesel::ConnectionError> for WikigraphError {
+    fn from(e: diesel::ConnectionError) -> Self {
+        WikigraphError::Connection(e)
+    }
+}
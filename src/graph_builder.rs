@@ -1,70 +1,463 @@
 //move create_graph code here
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::error::WikigraphError;
+use crate::graph_reader::GraphReader;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use memmap2::MmapMut;
 use std::{
     fs::{File, OpenOptions},
-    io::{BufWriter, Seek, Write},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
 };
 
+//All sizes are in bytes. ie: 4 * 4 = 16 bytes = 4 integers.
+pub const FILE_HEADER_SIZE: usize = 4 * 4;
+pub const NODE_HEADER_SIZE: usize = 4 * 4;
+//Set in the 1st integer of the file header when the 3rd integer of the node headers counts template links.
+//Without it, that integer is free for traversal like the first two, as it was before --template-links.
+pub const TEMPLATE_LINKS_FLAG: i32 = 1;
+//Version 3, the default: links are i64 byte offsets, so the graph can grow past 2 GiB.
+pub const VERSION: i32 = 3;
+pub const LINK_SIZE: usize = 8;
+//Version 1 has the same layout with i32 links. Still written on request and read, for existing graphs.
+pub const V1_VERSION: i32 = 1;
+pub const V1_LINK_SIZE: usize = 4;
+//Version 4 is version 3 with an EdgeData behind every link: the i64 byte offset, then the number of occurrences
+//and the flags as u32s.
+pub const EDGE_DATA_VERSION: i32 = 4;
+pub const EDGE_DATA_LINK_SIZE: usize = 16;
+//Set in the flags of a link that appears in the lead section of the page, before its first heading.
+pub const LEAD_SECTION_FLAG: u32 = 1;
+//Written in place of a link whose target is not in the graph. Never a valid byte offset.
+pub const DANGLING_LINK: i64 = -1;
+//Version 2 is a CSR layout with dense node ids, see CsrGraphBuilder.
+pub const CSR_VERSION: i32 = 2;
+pub const CSR_OFFSET_SIZE: usize = 8;
+pub const CSR_TARGET_SIZE: usize = 4;
+//DANGLING_LINK in a version 2 graph.
+pub const CSR_DANGLING_LINK: u32 = u32::MAX;
+//Byte offsets held in the targets array until CsrGraphBuilder::flush_writer turns them into node ids.
+const RAW_TARGET_SIZE: usize = 8;
+
+//What a version 4 graph keeps about a link besides its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeData {
+    //How many times the page links to the target. Repeated links are folded into one.
+    pub occurrences: u32,
+    //Whether one of them is in the lead section.
+    pub lead: bool,
+}
+
+impl EdgeData {
+    pub fn from_flags(occurrences: u32, flags: u32) -> Self {
+        EdgeData {
+            occurrences,
+            lead: flags & LEAD_SECTION_FLAG != 0,
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        if self.lead {
+            LEAD_SECTION_FLAG
+        } else {
+            0
+        }
+    }
+
+    //Folds another link to the same target into this one.
+    pub fn merge(&mut self, other: EdgeData) {
+        self.occurrences = self.occurrences.saturating_add(other.occurrences);
+        self.lead |= other.lead;
+    }
+}
+
 pub trait GraphBuilder {
-    fn write_file_header(&mut self);
-    fn write_node_header(&mut self, num_links: i32);
-    fn get_current_position(&mut self) -> u64;
-    fn write_value(&mut self, value: i32);
-    fn flush_writer(&mut self);
+    //Size of a link in the byte offsets this builder hands out (see get_current_position).
+    //The first pass lays the nodes out with it, so it has to match the builder used for the second pass.
+    fn link_size(&self) -> usize;
+    fn set_count(&mut self, count: i32);
+    fn write_file_header(&mut self) -> Result<(), WikigraphError>;
+    //The last `num_template_links` of the node's links come from templates (see LinkTemplate).
+    fn write_node_header(
+        &mut self,
+        num_links: i32,
+        num_template_links: i32,
+    ) -> Result<(), WikigraphError>;
+    fn get_current_position(&mut self) -> Result<u64, WikigraphError>;
+    fn write_value(&mut self, value: i64) -> Result<(), WikigraphError>;
+    //Whether the links carry an EdgeData. The first pass then folds repeated links into one.
+    fn has_edge_data(&self) -> bool;
+    //Goes right after the write_value of its link. Left out of graphs without edge data.
+    fn write_edge_data(&mut self, edge_data: EdgeData) -> Result<(), WikigraphError>;
+    fn flush_writer(&mut self) -> Result<(), WikigraphError>;
 }
+//Lets a builder picked at runtime (see `create`) be used wherever a GraphBuilder is expected.
+impl<T: GraphBuilder + ?Sized> GraphBuilder for Box<T> {
+    fn link_size(&self) -> usize {
+        (**self).link_size()
+    }
+    fn set_count(&mut self, count: i32) {
+        (**self).set_count(count)
+    }
+    fn write_file_header(&mut self) -> Result<(), WikigraphError> {
+        (**self).write_file_header()
+    }
+    fn write_node_header(
+        &mut self,
+        num_links: i32,
+        num_template_links: i32,
+    ) -> Result<(), WikigraphError> {
+        (**self).write_node_header(num_links, num_template_links)
+    }
+    fn get_current_position(&mut self) -> Result<u64, WikigraphError> {
+        (**self).get_current_position()
+    }
+    fn write_value(&mut self, value: i64) -> Result<(), WikigraphError> {
+        (**self).write_value(value)
+    }
+    fn has_edge_data(&self) -> bool {
+        (**self).has_edge_data()
+    }
+    fn write_edge_data(&mut self, edge_data: EdgeData) -> Result<(), WikigraphError> {
+        (**self).write_edge_data(edge_data)
+    }
+    fn flush_writer(&mut self) -> Result<(), WikigraphError> {
+        (**self).flush_writer()
+    }
+}
+
+fn header_missing() -> WikigraphError {
+    WikigraphError::Consistency(
+        "write_file_header must be called before writing to the graph".to_owned(),
+    )
+}
+
+//Picks the builder for a graph format version.
+pub fn create(
+    binary_graph_path: String,
+    version: i32,
+) -> Result<Box<dyn GraphBuilder>, WikigraphError> {
+    match version {
+        VERSION | V1_VERSION | EDGE_DATA_VERSION => Ok(Box::new(WikiBinaryGraphBuilder::new(
+            binary_graph_path,
+            version,
+        ))),
+        CSR_VERSION => Ok(Box::new(CsrGraphBuilder::new(binary_graph_path, LINK_SIZE))),
+        _ => Err(WikigraphError::Format(format!(
+            "unsupported graph version {}, expected {}, {}, {} or {}",
+            version, V1_VERSION, CSR_VERSION, VERSION, EDGE_DATA_VERSION
+        ))),
+    }
+}
+
+//Size of a link in a byte offset graph of `version`.
+pub fn link_size(version: i32) -> Result<usize, WikigraphError> {
+    match version {
+        VERSION => Ok(LINK_SIZE),
+        V1_VERSION => Ok(V1_LINK_SIZE),
+        EDGE_DATA_VERSION => Ok(EDGE_DATA_LINK_SIZE),
+        _ => Err(WikigraphError::Format(format!(
+            "unsupported graph version {}, expected {}, {} or {}",
+            version, V1_VERSION, VERSION, EDGE_DATA_VERSION
+        ))),
+    }
+}
+
 pub struct WikiBinaryGraphBuilder {
-    graph_buf_writer: BufWriter<File>,
+    binary_graph_path: String,
+    graph_buf_writer: Option<BufWriter<File>>,
     count: i32,
     version: i32,
+    template_links: bool,
 }
 
 impl WikiBinaryGraphBuilder {
-    pub fn new(binary_graph_path: String, count: i32, version: i32) -> Self {
+    //The file is only created once the header is written, so a builder can be handed to a Parser that only pre-processes.
+    pub fn new(binary_graph_path: String, version: i32) -> Self {
+        WikiBinaryGraphBuilder {
+            binary_graph_path,
+            graph_buf_writer: None,
+            count: 0,
+            version,
+            template_links: false,
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut BufWriter<File>, WikigraphError> {
+        self.graph_buf_writer.as_mut().ok_or_else(header_missing)
+    }
+}
+impl GraphBuilder for WikiBinaryGraphBuilder {
+    fn link_size(&self) -> usize {
+        match self.version {
+            V1_VERSION => V1_LINK_SIZE,
+            EDGE_DATA_VERSION => EDGE_DATA_LINK_SIZE,
+            _ => LINK_SIZE,
+        }
+    }
+    fn set_count(&mut self, count: i32) {
+        self.count = count;
+    }
+    fn write_node_header(
+        &mut self,
+        num_links: i32,
+        num_template_links: i32,
+    ) -> Result<(), WikigraphError> {
+        //2 integers are unused, first integer is used for traversal. The 3rd is the number of links that come from
+        //templates (see TEMPLATE_LINKS_FLAG), the number of links is the 4th integer.
+        self.template_links |= num_template_links > 0;
+        let writer = self.writer()?;
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_i32::<LittleEndian>(num_template_links)?;
+        writer.write_i32::<LittleEndian>(num_links)?;
+        Ok(())
+    }
+    fn write_file_header(&mut self) -> Result<(), WikigraphError> {
         let graph = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(binary_graph_path)
-            .unwrap();
+            .truncate(true)
+            .open(&self.binary_graph_path)?;
+        let (version, count) = (self.version, self.count);
+        let writer = self.graph_buf_writer.insert(BufWriter::new(graph));
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_i32::<LittleEndian>(version)?;
+        writer.write_i32::<LittleEndian>(count)?;
+        Ok(())
+    }
+    fn get_current_position(&mut self) -> Result<u64, WikigraphError> {
+        let writer = self.writer()?;
+        writer.flush()?;
+        Ok(writer.stream_position()?)
+    }
+    fn write_value(&mut self, value: i64) -> Result<(), WikigraphError> {
+        if self.version != V1_VERSION {
+            self.writer()?.write_i64::<LittleEndian>(value)?;
+            return Ok(());
+        }
+        let value = i32::try_from(value).map_err(|_| {
+            WikigraphError::Format(format!(
+                "byte offset {} does not fit the version 1 graph format, use version {}",
+                value, VERSION
+            ))
+        })?;
+        self.writer()?.write_i32::<LittleEndian>(value)?;
+        Ok(())
+    }
+    fn has_edge_data(&self) -> bool {
+        self.version == EDGE_DATA_VERSION
+    }
+    fn write_edge_data(&mut self, edge_data: EdgeData) -> Result<(), WikigraphError> {
+        if self.version != EDGE_DATA_VERSION {
+            return Ok(());
+        }
+        let writer = self.writer()?;
+        writer.write_u32::<LittleEndian>(edge_data.occurrences)?;
+        writer.write_u32::<LittleEndian>(edge_data.flags())?;
+        Ok(())
+    }
+    fn flush_writer(&mut self) -> Result<(), WikigraphError> {
+        let template_links = self.template_links;
+        let writer = self.writer()?;
+        if template_links {
+            writer.seek(SeekFrom::Start(0))?;
+            writer.write_i32::<LittleEndian>(TEMPLATE_LINKS_FLAG)?;
+            writer.seek(SeekFrom::End(0))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
 
-        let graph_buf_writer = BufWriter::new(graph);
-        WikiBinaryGraphBuilder {
-            graph_buf_writer,
-            count,
-            version,
+//Version 2: the file header, then num_nodes + 1 u64 offsets into the targets array (CSR layout),
+//then one u32 node id per link. The links of node i are targets[offsets[i]..offsets[i + 1]].
+//It is fed the same calls as WikiBinaryGraphBuilder, so links arrive as byte offsets, laid out with `link_size`.
+//Those are written as i64 and turned into node ids in flush_writer, once every node (and so every byte offset)
+//is known. The node ids take half the space, so the targets are packed and the file shrunk afterwards.
+pub struct CsrGraphBuilder {
+    binary_graph_path: String,
+    graph_buf_writer: Option<BufWriter<File>>,
+    count: i32,
+    link_size: usize,
+    //index of the first link of every node, plus the total number of links once flushed.
+    link_starts: Vec<u64>,
+    num_links: u64,
+}
+
+impl CsrGraphBuilder {
+    //`link_size` is LINK_SIZE, or V1_LINK_SIZE when the byte offsets come from a version 1 graph.
+    pub fn new(binary_graph_path: String, link_size: usize) -> Self {
+        CsrGraphBuilder {
+            binary_graph_path,
+            graph_buf_writer: None,
+            count: 0,
+            link_size,
+            link_starts: Vec::new(),
+            num_links: 0,
         }
     }
+
+    fn writer(&mut self) -> Result<&mut BufWriter<File>, WikigraphError> {
+        self.graph_buf_writer.as_mut().ok_or_else(header_missing)
+    }
+
+    fn targets_start(&self) -> usize {
+        FILE_HEADER_SIZE + (self.count as usize + 1) * CSR_OFFSET_SIZE
+    }
+
+    //Byte offset `node` would have in a byte offset graph.
+    fn byte_offset(&self, node: usize) -> u64 {
+        (FILE_HEADER_SIZE + node * NODE_HEADER_SIZE) as u64
+            + self.link_starts[node] * self.link_size as u64
+    }
+
+    //Binary search over the nodes, their byte offsets are increasing.
+    fn node_at(&self, byte_offset: u64) -> Option<u32> {
+        let (mut low, mut high) = (0, self.link_starts.len() - 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.byte_offset(mid).cmp(&byte_offset) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid as u32),
+            }
+        }
+        None
+    }
 }
-impl GraphBuilder for WikiBinaryGraphBuilder {
-    fn write_node_header(&mut self, num_links: i32) {
-        //3 integers are unused. The number of links is the 4th integer. first integer is used for traversal.
-        self.graph_buf_writer.write_i32::<LittleEndian>(0).unwrap();
-        self.graph_buf_writer.write_i32::<LittleEndian>(0).unwrap();
-        self.graph_buf_writer.write_i32::<LittleEndian>(0).unwrap();
-        self.graph_buf_writer
-            .write_i32::<LittleEndian>(num_links)
-            .unwrap();
-    }
-    fn write_file_header(&mut self) {
-        self.graph_buf_writer.write_i32::<LittleEndian>(0).unwrap();
-        self.graph_buf_writer.write_i32::<LittleEndian>(0).unwrap();
-        self.graph_buf_writer
-            .write_i32::<LittleEndian>(self.version)
-            .unwrap();
-        self.graph_buf_writer
-            .write_i32::<LittleEndian>(self.count)
-            .unwrap();
-    }
-    fn get_current_position(&mut self) -> u64 {
-        self.graph_buf_writer.flush().unwrap();
-        self.graph_buf_writer.stream_position().unwrap()
-    }
-    fn write_value(&mut self, value: i32) {
-        self.graph_buf_writer
-            .write_i32::<LittleEndian>(value)
-            .unwrap();
-    }
-    fn flush_writer(&mut self) {
-        self.graph_buf_writer.flush().unwrap();
+
+impl GraphBuilder for CsrGraphBuilder {
+    fn link_size(&self) -> usize {
+        self.link_size
+    }
+    fn set_count(&mut self, count: i32) {
+        self.count = count;
+    }
+    fn write_file_header(&mut self) -> Result<(), WikigraphError> {
+        let graph = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.binary_graph_path)?;
+        let count = self.count;
+        let targets_start = self.targets_start() as u64;
+        let writer = self.graph_buf_writer.insert(BufWriter::new(graph));
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_i32::<LittleEndian>(CSR_VERSION)?;
+        writer.write_i32::<LittleEndian>(count)?;
+        //the offsets are only known at the end, the targets are written right away behind the space for them.
+        writer.seek(SeekFrom::Start(targets_start))?;
+        self.link_starts.clear();
+        self.num_links = 0;
+        Ok(())
+    }
+    //there is no room for the number of template links, a version 2 graph does not tell them apart.
+    fn write_node_header(
+        &mut self,
+        _num_links: i32,
+        _num_template_links: i32,
+    ) -> Result<(), WikigraphError> {
+        self.writer()?;
+        self.link_starts.push(self.num_links);
+        Ok(())
+    }
+    //Position the node would be at in a byte offset graph, which is what the lookup table and the links hold.
+    fn get_current_position(&mut self) -> Result<u64, WikigraphError> {
+        self.writer()?;
+        Ok(
+            (FILE_HEADER_SIZE + self.link_starts.len() * NODE_HEADER_SIZE) as u64
+                + self.num_links * self.link_size as u64,
+        )
+    }
+    fn write_value(&mut self, value: i64) -> Result<(), WikigraphError> {
+        self.writer()?.write_i64::<LittleEndian>(value)?;
+        self.num_links += 1;
+        Ok(())
+    }
+    fn has_edge_data(&self) -> bool {
+        false
+    }
+    //no room for it either, the links of a version 2 graph are only node ids.
+    fn write_edge_data(&mut self, _edge_data: EdgeData) -> Result<(), WikigraphError> {
+        Ok(())
+    }
+    //Finishes the file, so unlike for version 1 it can only be called once all nodes are written.
+    fn flush_writer(&mut self) -> Result<(), WikigraphError> {
+        let writer = self.graph_buf_writer.take().ok_or_else(header_missing)?;
+        let graph = writer.into_inner().map_err(|e| e.into_error())?;
+        if self.link_starts.len() != self.count as usize {
+            return Err(WikigraphError::Consistency(format!(
+                "{} nodes were written but the file header says {}",
+                self.link_starts.len(),
+                self.count
+            )));
+        }
+        self.link_starts.push(self.num_links);
+        let targets_start = self.targets_start();
+        graph.set_len(targets_start as u64 + self.num_links * RAW_TARGET_SIZE as u64)?;
+        // Safety: the file was created by this builder and nothing else writes to it until it is done.
+        let mut mmap = unsafe { MmapMut::map_mut(&graph)? };
+        for (node, &link_start) in self.link_starts.iter().enumerate() {
+            let at = FILE_HEADER_SIZE + node * CSR_OFFSET_SIZE;
+            LittleEndian::write_u64(&mut mmap[at..at + CSR_OFFSET_SIZE], link_start);
+        }
+        //packed in place: target i is written at or before where raw link i was, after it was read.
+        for link in 0..self.num_links as usize {
+            let raw_at = targets_start + link * RAW_TARGET_SIZE;
+            let at = targets_start + link * CSR_TARGET_SIZE;
+            let target = match LittleEndian::read_i64(&mmap[raw_at..raw_at + RAW_TARGET_SIZE]) {
+                DANGLING_LINK => CSR_DANGLING_LINK,
+                byte_offset => u64::try_from(byte_offset)
+                    .ok()
+                    .and_then(|byte_offset| self.node_at(byte_offset))
+                    .ok_or_else(|| {
+                        WikigraphError::Consistency(format!(
+                            "link to byte offset {}, which is not the start of a node",
+                            byte_offset
+                        ))
+                    })?,
+            };
+            LittleEndian::write_u32(&mut mmap[at..at + CSR_TARGET_SIZE], target);
+        }
+        mmap.flush()?;
+        drop(mmap);
+        graph.set_len(targets_start as u64 + self.num_links * CSR_TARGET_SIZE as u64)?;
+        Ok(())
+    }
+}
+
+//Rewrites a byte offset graph (version 1, 3 or 4) as version 2. The edge data of version 4 is left out.
+pub fn convert_to_csr<P: AsRef<Path>>(
+    graph_path: P,
+    csr_path: String,
+) -> Result<(), WikigraphError> {
+    let graph = GraphReader::open(graph_path)?;
+    let mut builder = CsrGraphBuilder::new(csr_path, link_size(graph.version())?);
+    builder.set_count(graph.num_nodes());
+    builder.write_file_header()?;
+    let mut num_nodes = 0;
+    for node in graph.nodes() {
+        builder.write_node_header(node.num_links as i32, 0)?;
+        for link in graph.links_of(node.offset).into_iter().flatten() {
+            //graphs built before dangling links were marked point them at 0, the file header.
+            if link < FILE_HEADER_SIZE as i64 {
+                builder.write_value(DANGLING_LINK)?;
+            } else {
+                builder.write_value(link)?;
+            }
+        }
+        num_nodes += 1;
+    }
+    if num_nodes != graph.num_nodes() {
+        return Err(WikigraphError::Format(format!(
+            "the header says {} nodes but only {} could be read",
+            graph.num_nodes(),
+            num_nodes
+        )));
     }
+    builder.flush_writer()
 }
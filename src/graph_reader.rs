@@ -0,0 +1,440 @@
+use crate::error::WikigraphError;
+use crate::graph_builder::{
+    link_size, EdgeData, CSR_OFFSET_SIZE, CSR_TARGET_SIZE, CSR_VERSION, EDGE_DATA_LINK_SIZE,
+    FILE_HEADER_SIZE, NODE_HEADER_SIZE, TEMPLATE_LINKS_FLAG, V1_LINK_SIZE,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+/// Version of the graph at `path`, read from its file header. Version 2 graphs are read with `CsrGraphReader`,
+/// the others with `GraphReader`.
+pub fn graph_version<P: AsRef<Path>>(path: P) -> Result<i32, WikigraphError> {
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    File::open(path)?
+        .read_exact(&mut header)
+        .map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => WikigraphError::Format(format!(
+                "graph file is smaller than the {} byte file header",
+                FILE_HEADER_SIZE
+            )),
+            _ => e.into(),
+        })?;
+    Ok(LittleEndian::read_i32(&header[8..12]))
+}
+
+/// Read-only view of a graph written by `WikiBinaryGraphBuilder`, version 1 (i32 links), 3 (i64 links)
+/// or 4 (i64 links with their `EdgeData`).
+/// Nodes are addressed by their byte offset, the same value stored in the `lookup` table and in every link.
+pub struct GraphReader {
+    mmap: Mmap,
+    version: i32,
+    num_nodes: i32,
+    link_size: usize,
+    template_links: bool,
+}
+
+impl GraphReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, WikigraphError> {
+        let file = File::open(path)?;
+        // Safety: the graph is treated as immutable once built. Nothing in this crate writes to it while a reader is open.
+        let mmap = unsafe { Mmap::map(&file)? };
+        GraphReader::from_mmap(mmap)
+    }
+
+    fn from_mmap(mmap: Mmap) -> Result<Self, WikigraphError> {
+        if mmap.len() < FILE_HEADER_SIZE {
+            return Err(WikigraphError::Format(format!(
+                "graph file is {} bytes, smaller than the {} byte file header",
+                mmap.len(),
+                FILE_HEADER_SIZE
+            )));
+        }
+        //file header: the flags, 1 unused integer, the version and the number of nodes.
+        let template_links = LittleEndian::read_i32(&mmap[0..4]) & TEMPLATE_LINKS_FLAG != 0;
+        let version = LittleEndian::read_i32(&mmap[8..12]);
+        let num_nodes = LittleEndian::read_i32(&mmap[12..16]);
+        let link_size = link_size(version)?;
+        if num_nodes < 0 || (num_nodes as usize) * NODE_HEADER_SIZE > mmap.len() - FILE_HEADER_SIZE
+        {
+            return Err(WikigraphError::Format(format!(
+                "header claims {} nodes which do not fit in a {} byte file",
+                num_nodes,
+                mmap.len()
+            )));
+        }
+        Ok(GraphReader {
+            mmap,
+            version,
+            num_nodes,
+            link_size,
+            template_links,
+        })
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    /// Whether the node headers count template links, see `num_template_links`.
+    pub fn has_template_links(&self) -> bool {
+        self.template_links
+    }
+
+    /// Node count as recorded in the file header.
+    pub fn num_nodes(&self) -> i32 {
+        self.num_nodes
+    }
+
+    /// Number of links of the node starting at `offset`.
+    /// Returns None if `offset` cannot be the start of a node in this file.
+    pub fn num_links(&self, offset: usize) -> Option<usize> {
+        //`offset` comes from the caller, so it can be anything up to usize::MAX.
+        let links_start = offset.checked_add(NODE_HEADER_SIZE)?;
+        if offset < FILE_HEADER_SIZE
+            || !(offset - FILE_HEADER_SIZE).is_multiple_of(self.link_size)
+            || links_start > self.mmap.len()
+        {
+            return None;
+        }
+        //num_links is the 4th integer of the node header
+        let num_links = LittleEndian::read_i32(&self.mmap[offset + 12..offset + 16]);
+        let num_links = usize::try_from(num_links).ok()?;
+        let links_end = num_links
+            .checked_mul(self.link_size)?
+            .checked_add(links_start)?;
+        if links_end > self.mmap.len() {
+            return None;
+        }
+        //every other read of the node stays below links_end, so it can not overflow either.
+        Some(num_links)
+    }
+
+    /// Number of links of the node starting at `offset` that come from templates, they are its last links.
+    /// Always 0 unless the graph was built with --template-links.
+    pub fn num_template_links(&self, offset: usize) -> Option<usize> {
+        let num_links = self.num_links(offset)?;
+        //graphs without the flag may use the 3rd integer for traversal.
+        if !self.template_links {
+            return Some(0);
+        }
+        //the 3rd integer of the node header
+        let num_template_links = LittleEndian::read_i32(&self.mmap[offset + 8..offset + 12]);
+        usize::try_from(num_template_links)
+            .ok()
+            .filter(|&n| n <= num_links)
+    }
+
+    /// Byte offsets of the nodes linked from the node starting at `offset`.
+    pub fn links_of(&self, offset: usize) -> Option<Links<'_>> {
+        let num_links = self.num_links(offset)?;
+        let start = offset + NODE_HEADER_SIZE;
+        Some(Links {
+            bytes: &self.mmap[start..start + num_links * self.link_size],
+            link_size: self.link_size,
+        })
+    }
+
+    /// Whether the links carry an `EdgeData`, only version 4 graphs do.
+    pub fn has_edge_data(&self) -> bool {
+        self.link_size == EDGE_DATA_LINK_SIZE
+    }
+
+    /// How often, and whether in the lead section, the node starting at `offset` links to each of the
+    /// targets of `links_of`, in the same order. None if the graph has no edge data.
+    pub fn edge_data_of(&self, offset: usize) -> Option<EdgeDatas<'_>> {
+        if !self.has_edge_data() {
+            return None;
+        }
+        let num_links = self.num_links(offset)?;
+        let start = offset + NODE_HEADER_SIZE;
+        Some(EdgeDatas {
+            bytes: &self.mmap[start..start + num_links * EDGE_DATA_LINK_SIZE],
+        })
+    }
+
+    /// Iterates over every node in file order.
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes {
+            reader: self,
+            offset: FILE_HEADER_SIZE,
+        }
+    }
+}
+
+pub struct Links<'a> {
+    bytes: &'a [u8],
+    link_size: usize,
+}
+
+impl Iterator for Links<'_> {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let (link, rest) = self.bytes.split_at(self.link_size);
+        self.bytes = rest;
+        match self.link_size {
+            V1_LINK_SIZE => Some(LittleEndian::read_i32(link) as i64),
+            //the edge data of a version 4 link follows the byte offset
+            _ => Some(LittleEndian::read_i64(&link[..8])),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.bytes.len() / self.link_size;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for Links<'_> {}
+
+pub struct EdgeDatas<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for EdgeDatas<'_> {
+    type Item = EdgeData;
+
+    fn next(&mut self) -> Option<EdgeData> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let (link, rest) = self.bytes.split_at(EDGE_DATA_LINK_SIZE);
+        self.bytes = rest;
+        Some(EdgeData::from_flags(
+            LittleEndian::read_u32(&link[8..12]),
+            LittleEndian::read_u32(&link[12..16]),
+        ))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.bytes.len() / EDGE_DATA_LINK_SIZE;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for EdgeDatas<'_> {}
+
+/// A node yielded by `GraphReader::nodes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub offset: usize,
+    pub num_links: usize,
+}
+
+pub struct Nodes<'a> {
+    reader: &'a GraphReader,
+    offset: usize,
+}
+
+impl Iterator for Nodes<'_> {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        // a truncated trailing node ends the iteration instead of reading out of bounds
+        let num_links = self.reader.num_links(self.offset)?;
+        let node = Node {
+            offset: self.offset,
+            num_links,
+        };
+        self.offset += NODE_HEADER_SIZE + num_links * self.reader.link_size;
+        Some(node)
+    }
+}
+
+/// Read-only view of a version 2 (CSR) graph written by `CsrGraphBuilder`.
+/// Nodes are dense ids: node i is the i-th page in byte offset order, so the `lookup` table still maps titles
+/// to byte offsets. Dangling links are `CSR_DANGLING_LINK`.
+pub struct CsrGraphReader {
+    mmap: Mmap,
+    num_nodes: u32,
+    targets_start: usize,
+}
+
+impl CsrGraphReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, WikigraphError> {
+        let file = File::open(path)?;
+        // Safety: the graph is treated as immutable once built. Nothing in this crate writes to it while a reader is open.
+        let mmap = unsafe { Mmap::map(&file)? };
+        CsrGraphReader::from_mmap(mmap)
+    }
+
+    fn from_mmap(mmap: Mmap) -> Result<Self, WikigraphError> {
+        if mmap.len() < FILE_HEADER_SIZE {
+            return Err(WikigraphError::Format(format!(
+                "graph file is {} bytes, smaller than the {} byte file header",
+                mmap.len(),
+                FILE_HEADER_SIZE
+            )));
+        }
+        let version = LittleEndian::read_i32(&mmap[8..12]);
+        let num_nodes = LittleEndian::read_i32(&mmap[12..16]);
+        if version != CSR_VERSION {
+            return Err(WikigraphError::Format(format!(
+                "unsupported graph version {}, expected {}",
+                version, CSR_VERSION
+            )));
+        }
+        let num_nodes = u32::try_from(num_nodes).map_err(|_| {
+            WikigraphError::Format(format!("negative node count {} in the header", num_nodes))
+        })?;
+        let targets_start = (num_nodes as usize)
+            .checked_add(1)
+            .and_then(|offsets| offsets.checked_mul(CSR_OFFSET_SIZE))
+            .and_then(|offsets_size| offsets_size.checked_add(FILE_HEADER_SIZE))
+            .filter(|&targets_start| targets_start <= mmap.len())
+            .ok_or_else(|| {
+                WikigraphError::Format(format!(
+                    "header claims {} nodes which do not fit in a {} byte file",
+                    num_nodes,
+                    mmap.len()
+                ))
+            })?;
+        let reader = CsrGraphReader {
+            mmap,
+            num_nodes,
+            targets_start,
+        };
+        //the offsets come from the file, a corrupt one must not wrap around into a length that looks right.
+        let num_links = reader.link_start(num_nodes);
+        let file_len = num_links
+            .checked_mul(CSR_TARGET_SIZE as u64)
+            .and_then(|targets_size| targets_size.checked_add(reader.targets_start as u64));
+        if file_len != Some(reader.mmap.len() as u64) {
+            return Err(WikigraphError::Format(format!(
+                "offsets claim {} links, which does not match the {} byte file",
+                num_links,
+                reader.mmap.len()
+            )));
+        }
+        Ok(reader)
+    }
+
+    pub fn num_nodes(&self) -> u32 {
+        self.num_nodes
+    }
+
+    fn link_start(&self, node: u32) -> u64 {
+        let at = FILE_HEADER_SIZE + node as usize * CSR_OFFSET_SIZE;
+        LittleEndian::read_u64(&self.mmap[at..at + CSR_OFFSET_SIZE])
+    }
+
+    /// Number of links of `node`, None if there is no such node.
+    pub fn num_links(&self, node: u32) -> Option<usize> {
+        self.link_range(node).map(|(start, end)| end - start)
+    }
+
+    /// Node ids linked from `node`.
+    pub fn links_of(&self, node: u32) -> Option<CsrLinks<'_>> {
+        let (start, end) = self.link_range(node)?;
+        Some(CsrLinks {
+            bytes: &self.mmap[self.targets_start + start * CSR_TARGET_SIZE
+                ..self.targets_start + end * CSR_TARGET_SIZE],
+        })
+    }
+
+    fn link_range(&self, node: u32) -> Option<(usize, usize)> {
+        if node >= self.num_nodes {
+            return None;
+        }
+        let start = self.link_start(node);
+        let end = self.link_start(node + 1);
+        //the total was checked against the file length in open, a node can not point past it
+        if start > end || end > self.link_start(self.num_nodes) {
+            return None;
+        }
+        Some((usize::try_from(start).ok()?, usize::try_from(end).ok()?))
+    }
+}
+
+pub struct CsrLinks<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for CsrLinks<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let (link, rest) = self.bytes.split_at(CSR_TARGET_SIZE);
+        self.bytes = rest;
+        Some(LittleEndian::read_u32(link))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.bytes.len() / CSR_TARGET_SIZE;
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for CsrLinks<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{CsrGraphReader, GraphReader};
+    use crate::graph_builder::{CSR_VERSION, FILE_HEADER_SIZE, VERSION};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use std::path::PathBuf;
+
+    //Writes `values` as little endian integers to a file in the temp directory.
+    fn write_graph(name: &str, values: &[i32]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wikigraph-{}-{}", std::process::id(), name));
+        let mut bytes = Vec::new();
+        for &value in values {
+            bytes.write_i32::<LittleEndian>(value).unwrap();
+        }
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn offsets_past_the_file_are_not_nodes() {
+        //file header, then one node without links
+        let path = write_graph("offsets", &[0, 0, VERSION, 1, 0, 0, 0, 0]);
+        let graph = GraphReader::open(&path).unwrap();
+        assert_eq!(graph.num_links(FILE_HEADER_SIZE), Some(0));
+        assert!(graph.links_of(usize::MAX - 4).is_none());
+        assert!(graph.num_template_links(usize::MAX).is_none());
+        assert!(graph.links_of(usize::MAX - usize::MAX % 8).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn template_links_are_only_read_behind_the_flag() {
+        //one node with 2 links, 1 of them from a template, then the same graph with traversal data in the 3rd integer
+        let path = write_graph(
+            "template-links",
+            &[1, 0, VERSION, 1, 0, 0, 1, 2, 16, 0, 16, 0],
+        );
+        let graph = GraphReader::open(&path).unwrap();
+        assert!(graph.has_template_links());
+        assert_eq!(graph.num_template_links(FILE_HEADER_SIZE), Some(1));
+        std::fs::remove_file(path).unwrap();
+
+        let path = write_graph("scratch", &[0, 0, VERSION, 1, 0, 0, 7, 2, 16, 0, 16, 0]);
+        let graph = GraphReader::open(&path).unwrap();
+        assert!(!graph.has_template_links());
+        assert_eq!(graph.num_template_links(FILE_HEADER_SIZE), Some(0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_link_counts_that_overflow() {
+        //one node, offsets [0, 2^62]: 2^62 links of 4 bytes wrap around to a 0 byte targets array
+        let path = write_graph("csr-overflow", &[0, 0, CSR_VERSION, 1, 0, 0, 0, 1 << 30]);
+        assert!(CsrGraphReader::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        let path = write_graph("csr", &[0, 0, CSR_VERSION, 1, 0, 0, 1, 0, 7]);
+        let graph = CsrGraphReader::open(&path).unwrap();
+        assert_eq!(graph.links_of(0).unwrap().collect::<Vec<_>>(), [7]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
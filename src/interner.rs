@@ -0,0 +1,31 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+//Hands out dense u32 ids for titles, in the order they are first seen.
+//The map and the list of titles share one allocation per title, it is the largest thing the single pass keeps in memory.
+#[derive(Default)]
+pub struct Interner {
+    ids: HashMap<Arc<str>, u32>,
+    titles: Vec<Arc<str>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    pub fn intern(&mut self, title: &str) -> u32 {
+        if let Some(&id) = self.ids.get(title) {
+            return id;
+        }
+        let id = self.titles.len() as u32;
+        let title: Arc<str> = Arc::from(title);
+        self.ids.insert(Arc::clone(&title), id);
+        self.titles.push(title);
+        id
+    }
+
+    pub fn title(&self, id: u32) -> &str {
+        &self.titles[id as usize]
+    }
+}
@@ -0,0 +1,21 @@
+pub mod adj_list_handler;
+pub mod checkpoint;
+pub mod database_handler;
+pub mod dump_reader;
+pub mod error;
+pub mod graph_builder;
+pub mod graph_reader;
+pub mod interner;
+pub mod link_handler;
+pub mod link_metadata;
+pub mod manifest;
+pub mod models;
+pub mod multistream;
+pub mod namespaces;
+pub mod parser;
+pub mod path_finder;
+pub mod profile;
+pub mod schema;
+pub mod single_pass;
+pub mod utils;
+pub mod wikitext;
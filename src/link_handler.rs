@@ -1,22 +1,229 @@
-use std::thread::current;
-
 //move link extraction here
-use crate::utils::sanitize_string;
+use crate::profile::LinkTemplate;
+use crate::utils::normalize_title;
+use crate::wikitext::{self, Element, Kind, Node};
+use serde::Serialize;
+use std::str::FromStr;
 pub trait LinkHandler {
-    fn extract_links(&self, input: String) -> Vec<String>;
+    fn extract_links(&self, input: String) -> Vec<Link>;
+}
+
+//A link found on a page, with its target normalized.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Link {
+    pub target: String,
+    //Text the link is shown with: "movement" for [[Movement (politics)|movement]], the title as written without a `|`.
+    pub anchor: String,
+    //The part after the `#`, ie: "History" for [[Anarchism#History]].
+    pub section: Option<String>,
+    //Byte offset of the link's `[[` in the text of the page, or of the `{{` of the template it comes from.
+    pub position: usize,
+    //Found in one of the link templates of the profile instead of the text of the page.
+    pub from_template: bool,
+    //Written as [[:Target]]. Without the colon, [[Category:X]] puts the page in the category and [[File:X]] embeds
+    //the file instead of linking to them. Titles given to link templates always link, they count as written with it.
+    pub leading_colon: bool,
+}
+
+//Lets an extractor picked at runtime (see `create`) be used wherever a LinkHandler is expected.
+impl<T: LinkHandler + ?Sized> LinkHandler for Box<T> {
+    fn extract_links(&self, input: String) -> Vec<Link> {
+        (**self).extract_links(input)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkExtractor {
+    //Parses the wikitext, see WikitextLinkHandler.
+    Wikitext,
+    //The character scanner of WikiLinkHandler, for graphs that have to match ones built with it.
+    Simple,
+}
+
+impl FromStr for LinkExtractor {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "wikitext" => Ok(LinkExtractor::Wikitext),
+            "simple" => Ok(LinkExtractor::Simple),
+            _ => Err(format!(
+                "unknown link extractor {}, expected wikitext or simple",
+                input
+            )),
+        }
+    }
+}
+
+//`link_templates` are only used by the wikitext extractor.
+pub fn create(
+    extractor: LinkExtractor,
+    link_templates: Vec<LinkTemplate>,
+) -> Box<dyn LinkHandler + Sync> {
+    match extractor {
+        LinkExtractor::Wikitext => Box::new(WikitextLinkHandler::new(link_templates)),
+        LinkExtractor::Simple => Box::new(WikiLinkHandler),
+    }
+}
+
+//Finds the links on a page from the tree of its wikitext (see wikitext::parse), so nested templates are skipped
+//as a whole and a `<` that does not start a tag is text. Links inside templates, comments, <nowiki>, <math>,
+//<pre>... are left out, the ones in tables, <ref>s and file captions are kept.
+//The exception are `link_templates`, their links are extracted too and tagged as coming from a template.
+pub struct WikitextLinkHandler {
+    link_templates: Vec<LinkTemplate>,
+}
+
+impl WikitextLinkHandler {
+    pub fn new(link_templates: Vec<LinkTemplate>) -> Self {
+        WikitextLinkHandler { link_templates }
+    }
+
+    fn collect_links(&self, nodes: &[Node], from_template: bool, links: &mut Vec<Link>) {
+        for node in nodes {
+            let Node::Element(element) = node else {
+                continue;
+            };
+            match element.kind {
+                Kind::Link => {
+                    if let Some(mut link) = link_of(element) {
+                        link.from_template = from_template;
+                        links.push(link);
+                    }
+                    //links in the caption of a [[File:...]]
+                    self.collect_links(&element.children, from_template, links);
+                }
+                Kind::Template => self.collect_template_links(element, links),
+                Kind::Parameter => {}
+                Kind::Table | Kind::Tag(_) => {
+                    self.collect_links(&element.children, from_template, links)
+                }
+            }
+        }
+    }
+
+    fn collect_template_links(&self, element: &Element, links: &mut Vec<Link>) {
+        let Some(template) = element.template_name().and_then(|name| {
+            self.link_templates
+                .iter()
+                .find(|template| template.matches(&name))
+        }) else {
+            return;
+        };
+        for parameter in element.parts().skip(1) {
+            match wikitext::parameter_name(parameter) {
+                None if template.titles => {
+                    if let Some((target, written, section)) = link_target(parameter) {
+                        links.push(Link {
+                            target,
+                            anchor: written,
+                            section,
+                            position: element.start,
+                            from_template: true,
+                            leading_colon: true,
+                        });
+                    }
+                }
+                Some(name) if template.keeps_parameter(&name) => {
+                    self.collect_links(parameter, true, links)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl LinkHandler for WikitextLinkHandler {
+    fn extract_links(&self, text: String) -> Vec<Link> {
+        let mut links = Vec::new();
+        self.collect_links(&wikitext::parse(&text), false, &mut links);
+        links
+    }
 }
 
+//The Link of a [[...]] element, None if its target is not a valid title.
+fn link_of(element: &Element) -> Option<Link> {
+    let mut parts = element.parts();
+    let (target, written, section) = link_target(parts.next()?)?;
+    //the caption of a [[File:...]] is its last part, and [[Target|]] is shown as the target.
+    let anchor = parts
+        .last()
+        .map(display_text)
+        .filter(|anchor| !anchor.is_empty());
+    Some(Link {
+        leading_colon: written.starts_with(':'),
+        target,
+        anchor: anchor.unwrap_or(written),
+        section,
+        position: element.start,
+        from_template: false,
+    })
+}
+
+//Normalized target of a link (or a title in a template), the title as written and the #section.
+//None if it is not a valid title, ie: [[{{name}}]] or [[#section]].
+fn link_target(nodes: &[Node]) -> Option<(String, String, Option<String>)> {
+    let written = wikitext::plain_text(nodes)?;
+    let written = written.trim();
+    let (target, section) = match written.split_once('#') {
+        Some((target, section)) => (target.trim(), Some(section.trim())),
+        None => (written, None),
+    };
+    if target.contains(['<', '>', '[', ']', '{', '}', '\n']) {
+        return None;
+    }
+    let target = normalize_title(target);
+    if target.is_empty() {
+        return None;
+    }
+    let section = section
+        .filter(|section| !section.is_empty())
+        .map(str::to_owned);
+    Some((target, written.to_owned(), section))
+}
+
+//Text of `nodes` as it is shown on the page: templates and footnotes are left out, nested links show their anchor
+//and bold or italic markup is dropped.
+fn display_text(nodes: &[Node]) -> String {
+    fn push_text(nodes: &[Node], text: &mut String) {
+        for node in nodes {
+            match node {
+                Node::Text(part) | Node::Raw(part) => text.push_str(part),
+                Node::Pipe => text.push('|'),
+                Node::Element(element) => match element.kind {
+                    Kind::Link => match link_of(element) {
+                        Some(link) => text.push_str(&link.anchor),
+                        None => push_text(&element.children, text),
+                    },
+                    Kind::Table => push_text(&element.children, text),
+                    Kind::Template | Kind::Parameter | Kind::Tag(_) => {}
+                },
+            }
+        }
+    }
+    let mut text = String::new();
+    push_text(nodes, &mut text);
+    text.replace("'''", "")
+        .replace("''", "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+//Scans for [[ and ]] character by character. Nested templates end at the first }} and any < starts a tag,
+//which WikitextLinkHandler gets right.
 pub struct WikiLinkHandler;
 impl LinkHandler for WikiLinkHandler {
-    fn extract_links(&self, text: String) -> Vec<String> {
-        let mut links: Vec<String> = Vec::new();
+    fn extract_links(&self, text: String) -> Vec<Link> {
+        let mut links: Vec<Link> = Vec::new();
         let mut current_link = String::new();
         let mut inside_link = false;
         let mut inside_tag = false; // New variable to track if we're inside a tag
         let mut tag_depth = 0; // Track the depth of nested tags
 
-        let mut chars = text.chars().peekable();
-        while let Some(c) = chars.next() {
+        let mut link_start = 0;
+        let mut chars = text.char_indices().peekable();
+        while let Some((index, c)) = chars.next() {
             if inside_tag {
                 // Logic to handle skipping tags, including nested ones
                 if c == '<' {
@@ -32,17 +239,28 @@ impl LinkHandler for WikiLinkHandler {
                 continue;
             }
             match c {
-                '[' if chars.peek() == Some(&'[') => {
+                '[' if matches!(chars.peek(), Some((_, '['))) => {
                     // Detect starting "[["
                     chars.next(); // Skip the next '['
                     inside_link = true;
+                    link_start = index;
                     current_link.clear();
                 }
-                ']' if chars.peek() == Some(&']') => {
+                ']' if matches!(chars.peek(), Some((_, ']'))) => {
                     //end links
                     // Detect ending "]]"
                     chars.next(); // Skip the next ']' as it's part of the marker
                     if inside_link && !current_link.is_empty() {
+                        let written = current_link.split('|').next().unwrap_or_default();
+                        let section = written
+                            .split_once('#')
+                            .map(|(_, section)| section.trim().to_owned())
+                            .filter(|section| !section.is_empty());
+                        let anchor = match current_link.rsplit_once('|') {
+                            Some((_, anchor)) if !anchor.trim().is_empty() => anchor.trim(),
+                            _ => written.trim(),
+                        }
+                        .to_owned();
                         if current_link.contains('|') {
                             let mut split = current_link.split('|');
                             let link = split.next().unwrap();
@@ -54,15 +272,22 @@ impl LinkHandler for WikiLinkHandler {
                             let link = split.next().unwrap();
                             current_link = link.to_string();
                         }
-                        if current_link.contains("(disambiguation)")
-                            || sanitize_string(&current_link).is_empty()
-                        //make sure the sanitized version is valid
+                        //links to disambiguation pages are dropped by the parser, it knows the wiki's markers.
+                        if normalize_title(&current_link).is_empty()
+                        //make sure the normalized version is valid
                         {
                             inside_link = false;
                             current_link.clear();
                             continue;
                         } else {
-                            links.push(sanitize_string(&current_link));
+                            links.push(Link {
+                                target: normalize_title(&current_link),
+                                anchor,
+                                section,
+                                position: link_start,
+                                from_template: false,
+                                leading_colon: current_link.trim_start().starts_with(':'),
+                            });
                             inside_link = false;
                         }
                     }
@@ -72,32 +297,20 @@ impl LinkHandler for WikiLinkHandler {
                     inside_tag = true;
                     tag_depth = 1;
                 }
-                '{' if chars.peek() == Some(&'{') => {
+                '{' if matches!(chars.peek(), Some((_, '{'))) => {
                     //skip till the end
-                    while let Some(c) = chars.next() {
-                        if c == '}' && chars.peek() == Some(&'}') {
+                    while let Some((_, c)) = chars.next() {
+                        if c == '}' && matches!(chars.peek(), Some((_, '}'))) {
                             chars.next();
                             break;
                         }
                     }
                 }
 
+                //links into other namespaces (files, categories...) are kept, the parser filters them by namespace.
+                //a [[ inside a file caption starts a new link, so the links in captions are still found.
                 _ if inside_link && !inside_tag => {
                     current_link.push(c);
-                    if current_link == "File:"
-                        || current_link == "Wikipedia:"
-                        || current_link == "WP:"
-                        || current_link == "Template:"
-                        || current_link == "MOS:"
-                        || current_link == "Help:"
-                        || current_link == "Draft:"
-                        || current_link == "User:"
-                        || current_link == "Image:"
-                    {
-                        // we realize that we are in either a file, template, or wikipedia article namespace. We reseet
-                        inside_link = false;
-                        current_link.clear();
-                    }
                 }
                 _ => {}
             }
@@ -0,0 +1,61 @@
+use crate::error::WikigraphError;
+use crate::link_handler::Link;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+
+//Sidecar with everything known about the links of every node, one JSON object per line:
+//`{"node":0,"title":"Anarchism","links":[{"target":"Political philosophy","anchor":"political philosophy",...}]}`
+//`node` is the index of the node in the graph (the order of the lookup table by byte offset, and the node id of a
+//version 2 graph), so it does not change when dangling links are dropped. The links are every link extracted from
+//the page, in page order with the template links last, before duplicates, self-links and dangling links are left out.
+pub struct LinkMetadataWriter {
+    file: File,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    node: usize,
+    title: &'a str,
+    links: &'a [Link],
+}
+
+impl LinkMetadataWriter {
+    //Like the adjacency list, an existing file is only cut back once the first pass starts (see truncate).
+    pub fn new(file_path: &str) -> Result<Self, WikigraphError> {
+        Ok(LinkMetadataWriter {
+            file: OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(file_path)?,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        node: usize,
+        title: &str,
+        links: &[Link],
+    ) -> Result<(), WikigraphError> {
+        let mut line = serde_json::to_string(&Record { node, title, links }).map_err(|e| {
+            WikigraphError::Format(format!("could not serialize the links of {}: {}", title, e))
+        })?;
+        line.push('\n');
+        //one write per node, a crash does not leave half a line before a checkpoint.
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    //Size of what has been written so far, recorded in checkpoints.
+    pub fn position(&mut self) -> Result<u64, WikigraphError> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    //Drops everything after `len` bytes, new lines are appended from there.
+    pub fn truncate(&mut self, len: u64) -> Result<(), WikigraphError> {
+        self.file.set_len(len)?;
+        self.file.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+}
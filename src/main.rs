@@ -1,43 +1,298 @@
-use std::fs::File;
-mod adj_list_handler;
-mod database_handler;
-mod graph_builder;
-mod link_handler;
-mod models;
-mod parser;
-mod schema;
-mod utils;
-use parser::Parser;
+use clap::{Parser as ClapParser, Subcommand};
+use wikigraph::adj_list_handler::AdjacencyListFormat;
+use wikigraph::checkpoint::Checkpoint;
+use wikigraph::database_handler::DatabaseHandler;
+use wikigraph::graph_reader::{self, CsrGraphReader, GraphReader};
+use wikigraph::link_handler::LinkExtractor;
+use wikigraph::link_metadata::LinkMetadataWriter;
+use wikigraph::manifest::Manifest;
+use wikigraph::parser::{DanglingLinks, Parser, RemovedLinks};
+use wikigraph::path_finder::PathFinder;
+use wikigraph::profile::WikiProfile;
+use wikigraph::utils::normalize_title;
+use wikigraph::{adj_list_handler, database_handler, dump_reader, graph_builder, link_handler};
 extern crate chrono;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
-use crate::{database_handler::DatabaseHandler, graph_builder::GraphBuilder};
-
-const FILE_PATH: &str = "raw_data/enwiki-pages-articles.xml";
-const BINARY_GRAPH_PATH: &str = "raw_data/binary_graph.bin";
-const VERSION: i32 = 1;
-const ADJ_LIST_PATH: &str = "adjacency_list.txt";
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let mut database_handler = database_handler::PostgresDatabaseHandler::new(&db_url)?;
-    let graph_builder =
-        graph_builder::WikiBinaryGraphBuilder::new(BINARY_GRAPH_PATH.to_owned(), 9030425, VERSION);
-    let link_handler = link_handler::WikiLinkHandler;
-    let adj_list_handler = adj_list_handler::WikigraphAdjacencyListHandler::new(ADJ_LIST_PATH);
+#[derive(ClapParser)]
+#[command(about = "Compress a wikipedia xml dump into a traversable link graph")]
+struct Cli {
+    /// Wikipedia pages-articles xml dump, optionally compressed (.bz2, .gz or .zst)
+    #[arg(long, default_value = "raw_data/enwiki-pages-articles.xml.bz2")]
+    input: String,
+    /// Index of a pages-articles-multistream dump. When given, the first pass runs on all cores
+    #[arg(long)]
+    index: Option<String>,
+    /// Worker threads for the multistream first pass (defaults to the number of cores)
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Where the binary graph is written to / read from
+    #[arg(long, default_value = "raw_data/binary_graph.bin")]
+    graph: String,
+    /// Graph format written by build-graph: 3 (64 bit byte offsets), 1 (32 bit byte offsets), 2 (CSR with dense node ids)
+    /// or 4 (like 3, with how often every link occurs on the page and whether it is in the lead section)
+    #[arg(long, default_value_t = graph_builder::VERSION)]
+    graph_version: i32,
+    /// Intermediate adjacency list produced by the first pass
+    #[arg(long, default_value = "adjacency_list.txt")]
+    adj_list: String,
+    /// Format of the adjacency list: text (`offset|count|link|link` lines) or binary (length prefixed records)
+    #[arg(long, default_value = "text")]
+    adj_list_format: AdjacencyListFormat,
+    /// Checkpoint written during the first pass [default: <adj-list>.checkpoint]
+    #[arg(long)]
+    checkpoint: Option<String>,
+    /// Continue an interrupted first pass from its last checkpoint instead of starting over
+    #[arg(long)]
+    resume: bool,
+    /// What to do with links to pages that are not in the graph: drop, sentinel (written as -1) or fail
+    #[arg(long, default_value = "sentinel")]
+    dangling_links: DanglingLinks,
+    /// Write the targets of dangling links, with how often they are linked, to this file
+    #[arg(long)]
+    dangling_report: Option<String>,
+    /// How links are found in the wikitext: wikitext (a parser that handles nested templates and tags) or simple (the old character scanner)
+    #[arg(long, default_value = "wikitext")]
+    link_extractor: LinkExtractor,
+    /// Also take links from the templates listed in the profile (ie: {{Main}}, navboxes, infoboxes), tagged as template links in the graph
+    #[arg(long)]
+    template_links: bool,
+    /// Keep every link to the same target on a page instead of only the first one (version 4 graphs count them instead)
+    #[arg(long)]
+    keep_duplicate_links: bool,
+    /// Write the anchor text, #section and position of every link to this file, one JSON line per node
+    #[arg(long)]
+    link_metadata: Option<String>,
+    /// Language profile of the wiki the dump is from (see profiles/), English if not given
+    #[arg(long)]
+    profile: Option<String>,
+    /// Namespaces (by key, ie: 0 for articles, 14 for categories) whose pages and links make up the graph
+    #[arg(long, value_delimiter = ',', default_value = "0")]
+    namespaces: Vec<i32>,
+    /// postgres://... connection string, sqlite://path/to/file.db, or memory: (in-process, only useful with `all`)
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    database_url: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// First pass: parse the dump into the lookup/redirect tables and the adjacency list
+    Preprocess,
+    /// Second pass: turn the adjacency list + lookup table into the binary graph
+    BuildGraph {
+        /// Number of nodes written by the first pass [default: read from <adj-list>.manifest]
+        #[arg(long)]
+        node_count: Option<i32>,
+    },
+    /// Run both passes
+    All {
+        /// Build the graph in one pass over the dump, without the adjacency list
+        #[arg(long)]
+        single_pass: bool,
+    },
+    /// Print the shortest path between two pages
+    Path { from: String, to: String },
+    /// Rewrite the version 1, 3 or 4 graph at --graph as a version 2 (CSR) graph
+    Convert {
+        /// Where the version 2 graph is written to
+        output: String,
+    },
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    if let Command::Convert { output } = &cli.command {
+        graph_builder::convert_to_csr(&cli.graph, output.clone())?;
+        return Ok(());
+    }
+    let database_url = cli
+        .database_url
+        .as_deref()
+        .ok_or("--database-url or DATABASE_URL has to be set")?;
+    let mut database_handler = database_handler::connect(database_url)?;
+
+    if let Command::Path { from, to } = &cli.command {
+        return find_path(database_handler.as_mut(), &cli.graph, from, to);
+    }
+
+    let graph_builder = graph_builder::create(cli.graph.clone(), cli.graph_version)?;
+    let profile = match &cli.profile {
+        Some(profile) => WikiProfile::read(profile)?,
+        None => WikiProfile::default(),
+    };
+    let link_templates = match (cli.template_links, cli.link_extractor) {
+        (false, _) => Vec::new(),
+        (true, LinkExtractor::Wikitext) => profile.link_templates.clone(),
+        (true, LinkExtractor::Simple) => {
+            return Err("--template-links needs --link-extractor wikitext".into())
+        }
+    };
+    let link_handler = link_handler::create(cli.link_extractor, link_templates);
+    let adj_list_handler = adj_list_handler::open(&cli.adj_list, cli.adj_list_format)?;
     let mut parser = Parser::new(
-        File::open(FILE_PATH)?,
         link_handler,
         database_handler,
         adj_list_handler,
         graph_builder,
     );
-    //Rerun this later
-    let mut start = Instant::now();
-    // parser.pre_process_file().unwrap();
-    // println!("Pre-process time: {:?}", start.elapsed());
-    start = Instant::now();
-    parser.create_graph();
-    println!("Graph creation time: {:?}", start.elapsed());
+    parser.set_dangling_links(cli.dangling_links);
+    parser.set_keep_duplicate_links(cli.keep_duplicate_links);
+    parser.set_included_namespaces(&cli.namespaces);
+    parser.set_profile(profile);
+    if let Some(link_metadata) = &cli.link_metadata {
+        parser.set_link_metadata(LinkMetadataWriter::new(link_metadata)?);
+    }
+    if let Some(dangling_report) = &cli.dangling_report {
+        parser.set_dangling_report_path(dangling_report.into());
+    }
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+
+    if let Command::All { single_pass: true } = cli.command {
+        if cli.resume {
+            return Err("--resume only works with the two pass pipeline".into());
+        }
+        let scratch_path = PathBuf::from(format!("{}.scratch", cli.graph));
+        let start = Instant::now();
+        match &cli.index {
+            Some(index) => parser.build_single_pass_multistream(
+                Path::new(&cli.input),
+                Path::new(index),
+                scratch_path,
+            )?,
+            None => {
+                let (input, progress) = dump_reader::open_dump_with_progress(&cli.input)
+                    .map_err(|e| format!("could not open {}: {}", cli.input, e))?;
+                parser.set_input_progress(progress);
+                parser.build_single_pass(input, scratch_path)?
+            }
+        }
+        println!("Single pass time: {:?}", start.elapsed());
+        println!("Nodes written: {}", parser.get_count());
+        print_removed_links(&parser.removed_links());
+        return Ok(());
+    }
+
+    let manifest_path = format!("{}.manifest", cli.adj_list);
+    parser.set_manifest_path(manifest_path.clone().into());
+    if let Command::Preprocess | Command::All { .. } = cli.command {
+        let checkpoint_path = cli
+            .checkpoint
+            .clone()
+            .unwrap_or_else(|| format!("{}.checkpoint", cli.adj_list));
+        if cli.resume {
+            if database_url == "memory:" {
+                return Err("--resume needs a database that outlives the interrupted run".into());
+            }
+            let checkpoint = Checkpoint::read(&checkpoint_path)
+                .map_err(|e| format!("could not read checkpoint {}: {}", checkpoint_path, e))?;
+            parser.resume(checkpoint)?;
+        }
+        parser.set_checkpoint_path(checkpoint_path.into());
+        let start = Instant::now();
+        match &cli.index {
+            Some(index) => {
+                parser.pre_process_multistream(Path::new(&cli.input), Path::new(index))?
+            }
+            None => {
+                let (input, progress) = dump_reader::open_dump_with_progress(&cli.input)
+                    .map_err(|e| format!("could not open {}: {}", cli.input, e))?;
+                parser.set_input_progress(progress);
+                parser.pre_process_file(input)?
+            }
+        }
+        println!("Pre-process time: {:?}", start.elapsed());
+        println!("Nodes written: {}", parser.get_count());
+        //`all` prints them once the graph is built, with the ones found after redirects are resolved.
+        if let Command::Preprocess = cli.command {
+            print_removed_links(&parser.removed_links());
+        }
+    }
+    if let Command::BuildGraph { node_count } = cli.command {
+        match node_count {
+            Some(node_count) => parser.set_count(node_count),
+            None => {
+                let manifest = Manifest::read(&manifest_path)
+                    .map_err(|e| format!("could not read manifest {}: {}", manifest_path, e))?;
+                parser.use_manifest(manifest)?;
+            }
+        }
+    }
+    if let Command::BuildGraph { .. } | Command::All { .. } = cli.command {
+        let start = Instant::now();
+        parser.create_graph()?;
+        println!("Graph creation time: {:?}", start.elapsed());
+        print_removed_links(&parser.removed_links());
+    }
+
+    Ok(())
+}
+
+fn print_removed_links(removed_links: &RemovedLinks) {
+    println!(
+        "Links removed: {} duplicates, {} self-links",
+        removed_links.duplicate_links, removed_links.self_links
+    );
+}
+
+fn find_path(
+    database_handler: &mut dyn DatabaseHandler,
+    graph_path: &str,
+    from: &str,
+    to: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let from_entry = database_handler
+        .lookup_with_redirects(&normalize_title(from))
+        .map_err(|e| format!("could not find \"{}\": {}", from, e))?;
+    let to_entry = database_handler
+        .lookup_with_redirects(&normalize_title(to))
+        .map_err(|e| format!("could not find \"{}\": {}", to, e))?;
+
+    let start = Instant::now();
+    let (graph, csr_graph);
+    let path_finder = if graph_reader::graph_version(graph_path)? == graph_builder::CSR_VERSION {
+        //node ids are the positions of the pages in byteoffset order
+        csr_graph = CsrGraphReader::open(graph_path)?;
+        let mut offsets: Vec<usize> = database_handler
+            .read_offsets_into_memory()?
+            .into_iter()
+            .map(|(_, offset)| offset as usize)
+            .collect();
+        offsets.sort_unstable();
+        PathFinder::from_csr(&csr_graph, offsets)?
+    } else {
+        graph = GraphReader::open(graph_path)?;
+        PathFinder::new(&graph)
+    };
+    println!("Loaded graph in {:?}", start.elapsed());
 
+    let start = Instant::now();
+    let path =
+        path_finder.shortest_path(from_entry.byteoffset as usize, to_entry.byteoffset as usize);
+    println!("Search time: {:?}", start.elapsed());
+    match path {
+        Some(path) => {
+            let mut titles = Vec::with_capacity(path.len());
+            for offset in path {
+                titles.push(database_handler.look_up_offset(offset as i64)?.title);
+            }
+            println!("{}", titles.join(" -> "));
+        }
+        None => println!("No path from \"{}\" to \"{}\"", from, to),
+    }
     Ok(())
 }
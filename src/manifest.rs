@@ -0,0 +1,39 @@
+use crate::error::WikigraphError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+//Written next to the adjacency list once the first pass finishes, so the second pass does not have to be told
+//how many nodes there are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub node_count: i32,
+    //Link size the byte offsets were laid out with, see GraphBuilder::link_size.
+    pub link_size: usize,
+    //Set while build-graph has the lookup table at other byte offsets than the adjacency list, because links were
+    //left out of the graph (ie: --dangling-links drop). Its offsets are not checked against the adjacency list then.
+    #[serde(default)]
+    pub relocated: bool,
+    //Links the first pass left out, see RemovedLinks. build-graph adds its own to them in the summary.
+    #[serde(default)]
+    pub duplicate_links: u64,
+    #[serde(default)]
+    pub self_links: u64,
+}
+
+impl Manifest {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, WikigraphError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            WikigraphError::Format(format!("invalid manifest {}: {}", path.display(), e))
+        })
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), WikigraphError> {
+        let contents = serde_json::to_string(self)
+            .map_err(|e| WikigraphError::Format(format!("could not serialize manifest: {}", e)))?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
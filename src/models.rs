@@ -1,18 +1,17 @@
 use crate::schema::*;
 use diesel::prelude::*;
-use diesel::query_dsl::QueryDsl;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 
-#[derive(Insertable, Queryable, Serialize, Selectable, QueryableByName, Debug)]
-#[table_name = "lookup"]
+#[derive(Insertable, Queryable, Serialize, Selectable, QueryableByName, Debug, Clone)]
+#[diesel(table_name = lookup)]
 pub struct LookupEntry {
     pub title: String,
-    pub byteoffset: i32,
+    pub byteoffset: i64,
     pub length: i32,
 }
 
-#[derive(Insertable, Queryable, QueryableByName, Selectable, Serialize, Debug)]
-#[table_name = "redirect"]
+#[derive(Insertable, Queryable, QueryableByName, Selectable, Serialize, Debug, Clone)]
+#[diesel(table_name = redirect)]
 pub struct RedirectEntry {
     pub redirect_from: String,
     pub redirect_to: String,
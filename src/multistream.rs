@@ -0,0 +1,68 @@
+use crate::dump_reader::open_dump;
+use crate::error::WikigraphError;
+use bzip2::read::BzDecoder;
+use std::fs::File;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::path::Path;
+
+//Byte range of one bz2 stream inside a pages-articles-multistream.xml.bz2 dump.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+//The index has one `stream_offset:page_id:title` line per page, pages of the same stream share the offset.
+//The first stream of the dump only holds <siteinfo>, and the last one the closing </mediawiki>,
+//neither is listed in the index so neither is returned.
+pub fn read_stream_ranges(
+    dump_path: &Path,
+    index_path: &Path,
+) -> Result<Vec<StreamRange>, WikigraphError> {
+    let mut offsets: Vec<u64> = Vec::new();
+    for line in open_dump(index_path)?.lines() {
+        let line = line?;
+        let offset = line
+            .split(':')
+            .next()
+            .and_then(|offset| offset.parse::<u64>().ok())
+            .ok_or_else(|| {
+                WikigraphError::Format(format!("malformed multistream index line: {}", line))
+            })?;
+        match offsets.last() {
+            Some(&last) if last == offset => continue,
+            Some(&last) if last > offset => {
+                return Err(WikigraphError::Format(format!(
+                    "multistream index is not sorted at offset {}",
+                    offset
+                )))
+            }
+            _ => offsets.push(offset),
+        }
+    }
+
+    //the last indexed stream runs up to the footer stream. read_stream stops at the end of the first
+    //bz2 stream it sees, so using the file length as the end is enough.
+    let dump_len = std::fs::metadata(dump_path)?.len();
+    if let Some(&last) = offsets.last().filter(|&&last| last >= dump_len) {
+        return Err(WikigraphError::Format(format!(
+            "multistream index points at byte {} but the dump is only {} bytes, is it the index of another dump?",
+            last, dump_len
+        )));
+    }
+    let ends = offsets.iter().skip(1).copied().chain(Some(dump_len));
+    Ok(offsets
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| StreamRange { start, end })
+        .collect())
+}
+
+//Decompresses a single stream. The result is a fragment of <page> elements without a root element.
+pub fn read_stream(dump_path: &Path, range: &StreamRange) -> Result<Vec<u8>, WikigraphError> {
+    let mut file = File::open(dump_path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut xml = Vec::new();
+    BzDecoder::new(file.take(range.end - range.start)).read_to_end(&mut xml)?;
+    Ok(xml)
+}
@@ -0,0 +1,124 @@
+use crate::utils::{NAMESPACES, NAMESPACE_ALIASES};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+
+//The main (article) namespace.
+pub const MAIN_NAMESPACE: i32 = 0;
+//A [[link]] into one of these embeds a file or puts the page in a category, unless it is written [[:File:...]].
+pub const FILE_NAMESPACE: i32 = 6;
+pub const CATEGORY_NAMESPACE: i32 = 14;
+
+//Namespaces of a wiki by name, and which of them end up in the graph.
+//The canonical English names work on every wiki, the localized ones are added from the <siteinfo> of the dump.
+#[derive(Debug, Clone)]
+pub struct Namespaces {
+    //lowercased name -> namespace key
+    keys: HashMap<String, i32>,
+    included: HashSet<i32>,
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        let mut namespaces = Namespaces {
+            keys: HashMap::new(),
+            included: HashSet::from([MAIN_NAMESPACE]),
+        };
+        for (key, name) in NAMESPACES {
+            namespaces.add(key, name);
+        }
+        for (alias, name) in NAMESPACE_ALIASES {
+            let (key, _) = NAMESPACES.iter().find(|(_, n)| *n == name).unwrap();
+            namespaces.add(*key, alias);
+        }
+        namespaces
+    }
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        Namespaces::default()
+    }
+
+    pub fn add(&mut self, key: i32, name: &str) {
+        if !name.is_empty() {
+            self.keys.insert(name.replace('_', " ").to_lowercase(), key);
+        }
+    }
+
+    //Only pages and links in these namespaces become part of the graph. Defaults to the main namespace.
+    pub fn set_included(&mut self, keys: &[i32]) {
+        self.included = keys.iter().copied().collect();
+    }
+
+    pub fn is_included(&self, key: i32) -> bool {
+        self.included.contains(&key)
+    }
+
+    //Namespace of a title from its prefix. Titles without a known prefix (ie: "Star Wars: Andor") are in the main namespace.
+    pub fn key_of(&self, title: &str) -> i32 {
+        let title = title.strip_prefix(':').unwrap_or(title);
+        title
+            .split_once(':')
+            .and_then(|(prefix, _)| {
+                self.keys
+                    .get(&prefix.replace('_', " ").trim().to_lowercase())
+            })
+            .copied()
+            .unwrap_or(MAIN_NAMESPACE)
+    }
+
+    pub fn includes_title(&self, title: &str) -> bool {
+        self.is_included(self.key_of(title))
+    }
+
+    //Whether a link to `title` written without a leading colon embeds a file or adds the page to a category.
+    pub fn is_inclusion(&self, title: &str) -> bool {
+        matches!(self.key_of(title), FILE_NAMESPACE | CATEGORY_NAMESPACE)
+    }
+}
+
+//Reads the <namespaces> of a <siteinfo> into `namespaces`, the reader has to be right after the <siteinfo> tag.
+pub(crate) fn read_namespaces<R: BufRead>(
+    file_reader: &mut Reader<R>,
+    buf: &mut Vec<u8>,
+    namespaces: &mut Namespaces,
+) -> Result<(), quick_xml::Error> {
+    loop {
+        buf.clear();
+        match file_reader.read_event_into(buf)? {
+            Event::Start(e) if e.name().as_ref() == b"namespace" => {
+                let key = e
+                    .try_get_attribute("key")?
+                    .and_then(|key| std::str::from_utf8(&key.value).ok()?.parse().ok());
+                if let (Some(key), Event::Text(name)) = (key, file_reader.read_event_into(buf)?) {
+                    namespaces.add(key, &name.unescape()?);
+                }
+            }
+            Event::End(e) if e.name().as_ref() == b"siteinfo" => return Ok(()),
+            Event::Eof => return Ok(()),
+            _ => (),
+        }
+    }
+}
+
+//Reads up to the end of the <siteinfo>, or the first <page> if there is none. Only for input that is not parsed
+//any further: the start of a resumed dump that is skipped anyways, or the first stream of a multistream dump.
+pub(crate) fn read_siteinfo<R: BufRead>(
+    file_reader: &mut Reader<R>,
+    namespaces: &mut Namespaces,
+) -> Result<(), quick_xml::Error> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match file_reader.read_event_into(&mut buf)? {
+            Event::Start(e) if e.name().as_ref() == b"siteinfo" => {
+                return read_namespaces(file_reader, &mut buf, namespaces)
+            }
+            Event::Start(e) if e.name().as_ref() == b"page" => return Ok(()),
+            Event::Eof => return Ok(()),
+            _ => (),
+        }
+    }
+}
@@ -1,288 +1,876 @@
-use crate::adj_list_handler::{AdjacencyListHandler, WikigraphAdjacencyListHandler};
-use crate::database_handler::{DatabaseHandler, PostgresDatabaseHandler};
-use crate::graph_builder::{GraphBuilder, WikiBinaryGraphBuilder};
-use crate::link_handler::{LinkHandler, WikiLinkHandler};
+use crate::adj_list_handler::{AdjacencyListHandler, AdjacencyRecord};
+use crate::checkpoint::Checkpoint;
+use crate::database_handler::DatabaseHandler;
+use crate::dump_reader::InputProgress;
+use crate::error::WikigraphError;
+use crate::graph_builder::{
+    EdgeData, GraphBuilder, DANGLING_LINK, FILE_HEADER_SIZE, NODE_HEADER_SIZE,
+};
+use crate::link_handler::{Link, LinkHandler};
+use crate::link_metadata::LinkMetadataWriter;
+use crate::manifest::Manifest;
 use crate::models::{LookupEntry, RedirectEntry};
-use crate::schema::lookup::{self, byteoffset};
-use crate::utils::sanitize_string;
-use core::panic;
-use diesel::result::DatabaseErrorKind;
-use diesel::result::Error::DatabaseError;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use crate::multistream::{self, StreamRange};
+use crate::namespaces::{read_namespaces, read_siteinfo, Namespaces};
+use crate::profile::WikiProfile;
+use crate::single_pass::SinglePass;
+use crate::utils::normalize_title;
+use crate::wikitext::lead_section_end;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as fmtWrite;
 use std::fs::File;
-use std::io::{empty, BufRead, BufReader};
-use std::process::exit;
+use std::hash::Hash;
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-//All sizes are in bytes. ie: 4 * 4 = 16 bytes = 4 integers.
-const FILE_HEADER_SIZE: usize = 4 * 4;
-const NODE_HEADER_SIZE: usize = 4 * 4;
-const LINK_SIZE: usize = 4;
+//Pages queued before their lookup/redirect rows are inserted in one go.
+const INSERT_BATCH_SIZE: usize = 1000;
+//How many multistream streams each worker thread gets per batch.
+const STREAMS_PER_THREAD: usize = 16;
 
-const ADJACENCY_LIST_PATH: &str = "adjacency_list.txt";
-const NUM_ARTICLES: u64 = 8395904;
-const NUM_SIMPLE_ARTICLES: u64 = 248780;
-
-pub struct Parser {
-    file_reader: quick_xml::Reader<std::io::BufReader<File>>,
+//Each stage sits behind its trait, so other link extractors, databases or output formats can be plugged in.
+pub struct Parser<L, D, A, G>
+where
+    L: LinkHandler,
+    D: DatabaseHandler,
+    A: AdjacencyListHandler,
+    G: GraphBuilder,
+{
     count: i32,
-    link_handler: WikiLinkHandler,
-    database_handler: PostgresDatabaseHandler,
-    adj_list_handler: WikigraphAdjacencyListHandler,
-    graph_builder: WikiBinaryGraphBuilder,
+    link_handler: L,
+    database_handler: D,
+    adj_list_handler: A,
+    graph_builder: G,
+    checkpoint_path: Option<PathBuf>,
+    resume_from: Option<Checkpoint>,
+    manifest_path: Option<PathBuf>,
+    //Whether the lookup table is at other byte offsets than the adjacency list, see Manifest::relocated.
+    relocated: bool,
+    input_progress: Option<InputProgress>,
+    dangling_links: DanglingLinks,
+    dangling_report_path: Option<PathBuf>,
+    link_metadata: Option<LinkMetadataWriter>,
+    keep_duplicate_links: bool,
+    removed_links: RemovedLinks,
+    //Left out by create_graph (or the single pass) once redirects are resolved, on top of removed_links.
+    resolved_removed_links: RemovedLinks,
+    namespaces: Namespaces,
+    profile: WikiProfile,
 }
 
-impl Parser {
+impl<L, D, A, G> Parser<L, D, A, G>
+where
+    L: LinkHandler,
+    D: DatabaseHandler,
+    A: AdjacencyListHandler,
+    G: GraphBuilder,
+{
     pub fn new(
-        file: std::fs::File,
-        link_handler: WikiLinkHandler,
-        database_handler: PostgresDatabaseHandler,
-        adj_list_handler: WikigraphAdjacencyListHandler,
-        graph_builder: WikiBinaryGraphBuilder,
-    ) -> Parser {
-        let mut file_reader = Reader::from_reader(BufReader::new(file));
-        file_reader.trim_text(true);
+        link_handler: L,
+        database_handler: D,
+        adj_list_handler: A,
+        graph_builder: G,
+    ) -> Self {
         Parser {
-            file_reader,
             count: 0,
             link_handler,
             database_handler,
             adj_list_handler,
             graph_builder,
+            checkpoint_path: None,
+            resume_from: None,
+            manifest_path: None,
+            relocated: false,
+            input_progress: None,
+            dangling_links: DanglingLinks::Sentinel,
+            dangling_report_path: None,
+            link_metadata: None,
+            keep_duplicate_links: false,
+            removed_links: RemovedLinks::default(),
+            resolved_removed_links: RemovedLinks::default(),
+            namespaces: Namespaces::new(),
+            profile: WikiProfile::default(),
         }
     }
     pub fn set_count(&mut self, count: i32) {
         self.count = count;
     }
+    pub fn get_count(&self) -> i32 {
+        self.count
+    }
+    //The first pass writes a checkpoint here after every flush, and removes it once it finishes.
+    pub fn set_checkpoint_path(&mut self, path: PathBuf) {
+        self.checkpoint_path = Some(path);
+    }
+    //The first pass writes a Manifest here once it finishes, create_graph updates it when it moves the lookup table.
+    pub fn set_manifest_path(&mut self, path: PathBuf) {
+        self.manifest_path = Some(path);
+    }
+    //Takes the node count for create_graph from the manifest of the first pass.
+    pub fn use_manifest(&mut self, manifest: Manifest) -> Result<(), WikigraphError> {
+        if manifest.link_size != self.graph_builder.link_size() {
+            return Err(WikigraphError::Consistency(format!(
+                "the first pass laid the nodes out for {} byte links but the graph builder writes {} byte links, \
+                build the graph with the version the first pass was run for",
+                manifest.link_size,
+                self.graph_builder.link_size()
+            )));
+        }
+        self.set_count(manifest.node_count);
+        self.relocated = manifest.relocated;
+        self.removed_links = RemovedLinks {
+            duplicate_links: manifest.duplicate_links,
+            self_links: manifest.self_links,
+        };
+        Ok(())
+    }
+    //Read position in the file behind the input of pre_process_file and build_single_pass, shown as their progress.
+    //Without it the progress is the position in the decompressed xml, with no total.
+    pub fn set_input_progress(&mut self, progress: InputProgress) {
+        self.input_progress = Some(progress);
+    }
+    pub fn set_dangling_links(&mut self, dangling_links: DanglingLinks) {
+        self.dangling_links = dangling_links;
+    }
+    //create_graph writes the targets of dangling links here, with how often each of them is linked.
+    pub fn set_dangling_report_path(&mut self, path: PathBuf) {
+        self.dangling_report_path = Some(path);
+    }
+    //The first pass (or the single pass) writes the anchor text, section and position of every link here.
+    pub fn set_link_metadata(&mut self, link_metadata: LinkMetadataWriter) {
+        self.link_metadata = Some(link_metadata);
+    }
+    //Keeps every link to the same target instead of only the first one. Version 4 graphs count them either way.
+    pub fn set_keep_duplicate_links(&mut self, keep_duplicate_links: bool) {
+        self.keep_duplicate_links = keep_duplicate_links;
+    }
+    //Links the last first pass left out of the graph, and the ones create_graph (or the single pass) left out after it.
+    pub fn removed_links(&self) -> RemovedLinks {
+        let mut removed_links = self.removed_links;
+        removed_links += self.resolved_removed_links;
+        removed_links
+    }
+    //Pages and links in other namespaces are left out of the graph. Defaults to the main namespace only.
+    pub fn set_included_namespaces(&mut self, keys: &[i32]) {
+        self.namespaces.set_included(keys);
+    }
+    //Language specific markers of the wiki the dump is from, English by default.
+    pub fn set_profile(&mut self, profile: WikiProfile) {
+        for (alias, &key) in profile.namespace_aliases.iter() {
+            self.namespaces.add(key, alias);
+        }
+        self.profile = profile;
+    }
+    //Rolls the adjacency list and the lookup table back to `checkpoint`, the next pre_process call continues from it.
+    //Redirects inserted after the checkpoint are left alone, inserting them again is a no-op.
+    pub fn resume(&mut self, checkpoint: Checkpoint) -> Result<(), WikigraphError> {
+        let adj_list_len = self.adj_list_handler.position()?;
+        if adj_list_len < checkpoint.adj_list_len {
+            return Err(WikigraphError::Consistency(format!(
+                "the adjacency list is {} bytes but the checkpoint expects at least {}, was it truncated or overwritten?",
+                adj_list_len, checkpoint.adj_list_len
+            )));
+        }
+        self.adj_list_handler.truncate(checkpoint.adj_list_len)?;
+        if let Some(link_metadata) = &mut self.link_metadata {
+            let len = checkpoint.link_metadata_len.ok_or_else(|| {
+                WikigraphError::Consistency(
+                    "the interrupted run did not write link metadata, resume it without --link-metadata"
+                        .to_owned(),
+                )
+            })?;
+            link_metadata.truncate(len)?;
+        }
+        self.database_handler
+            .delete_lookup_entries_from(checkpoint.next_offset() as i64)?;
+        self.resume_from = Some(checkpoint);
+        Ok(())
+    }
     //First pass to generate lookup table with computed byte offsets + create text file with adjacency list
-    pub fn pre_process_file(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let bar = ProgressBar::new(NUM_ARTICLES);
-        bar.set_style(
-            ProgressStyle::with_template(
-                "[{wide_bar:.cyan/blue}] [{elapsed_precise}] {pos:>7}/{len:7} ({eta})",
-            )
-            .unwrap()
-            .with_key("eta", |state: &ProgressState, w: &mut dyn fmtWrite| {
-                write!(w, "{:.1}hrs", state.eta().as_secs_f64() / 3600.0).unwrap()
-            })
-            .progress_chars("#>-"),
-        );
+    pub fn pre_process_file<R: BufRead>(&mut self, input: R) -> Result<(), WikigraphError> {
+        let (mut state, skip) = self.start_pre_process(false)?;
+        let mut input = input;
+        let mut skipped = 0;
+        if skip > 0 {
+            //the <siteinfo> is in the skipped part, only it is parsed.
+            let mut site_reader = Reader::from_reader(input.by_ref());
+            read_siteinfo(&mut site_reader, &mut self.namespaces).map_err(|source| {
+                WikigraphError::Xml {
+                    position: site_reader.buffer_position() as u64,
+                    source,
+                }
+            })?;
+            skipped = site_reader.buffer_position() as u64;
+        }
+        //decompressed streams cannot seek, the skipped part still has to be read but is not parsed.
+        skipped += std::io::copy(
+            &mut input.by_ref().take(skip.saturating_sub(skipped)),
+            &mut std::io::sink(),
+        )?;
+        if skipped != skip {
+            return Err(WikigraphError::Consistency(format!(
+                "the dump ends at byte {}, before the checkpoint at byte {}. Is it the same dump?",
+                skipped, skip
+            )));
+        }
+        let mut file_reader = Reader::from_reader(input);
+        file_reader.trim_text(true);
+        //after a resume the reader starts in the middle of <mediawiki>, its closing tag has no matching start.
+        file_reader.check_end_names(skip == 0);
+        let bar = self.input_progress_bar();
 
         let mut buf: Vec<u8> = Vec::new();
-        let mut prev_offset: usize = FILE_HEADER_SIZE;
-        let mut prev_length: usize = 0;
-        let mut count = 0;
-
         loop {
-            match self.file_reader.read_event_into(&mut buf) {
-                Err(e) => panic!(
-                    "Error at position {}: {:?}",
-                    self.file_reader.buffer_position(),
-                    e
-                ),
+            match read_next_page(&mut file_reader, &mut buf, &mut self.namespaces) {
+                Err(source) => {
+                    return Err(WikigraphError::Xml {
+                        position: skip + file_reader.buffer_position() as u64,
+                        source,
+                    })
+                }
+                Ok(Some(page)) => {
+                    if let Some(page) =
+                        prepare_page(&self.link_handler, &self.namespaces, &self.profile, page)
+                    {
+                        self.record_page(page, &mut state)?;
+                        if state.is_flushed() {
+                            let position = skip + file_reader.buffer_position() as u64;
+                            self.write_checkpoint(&state, position, false)?;
+                        }
+                    }
+                    bar.set_position(
+                        self.input_position(skip + file_reader.buffer_position() as u64),
+                    );
+                }
                 // exits the loop when reaching end of file
-                Ok(Event::Eof) => {
-                    self.set_count(count);
+                Ok(None) => {
+                    self.flush_pages(&mut state)?;
+                    self.finish_pre_process(&state)?;
                     bar.finish();
                     return Ok(());
                 }
-                Ok(Event::Start(e)) => {
-                    if e.name().as_ref() == b"page" {
-                        let mut page_title = String::new();
-                        let mut page_txt: String = String::new();
-                        let mut is_redirect: bool = false;
-                        buf.clear();
-                        loop {
-                            match self.file_reader.read_event_into(&mut buf) {
-                                Ok(Event::Start(e)) => {
-                                    if e.name().as_ref() == b"title" {
-                                        let text_event = self.file_reader.read_event_into(&mut buf);
-                                        if let Ok(Event::Text(e)) = text_event {
-                                            if e.unescape()
-                                                .unwrap()
-                                                .into_owned()
-                                                .contains("Wikipedia:")
-                                            {
-                                                break;
-                                            }
-                                            page_title = e.unescape().unwrap().into_owned();
-                                        }
-                                        continue;
-                                    }
-                                    if e.name().as_ref() == b"text" {
-                                        let text_event = self.file_reader.read_event_into(&mut buf);
-                                        if let Ok(Event::Text(e)) = text_event {
-                                            page_txt = e.unescape().unwrap().into_owned();
-                                        }
-                                    }
-                                    continue;
-                                }
-                                Ok(Event::End(e)) => {
-                                    if e.name().as_ref() == b"page" {
-                                        //Reached </page> tag
-                                        break;
-                                    }
-                                }
-                                Ok(Event::Eof) => break,
-                                Ok(Event::Empty(e)) => {
-                                    if e.name().as_ref() == b"redirect" {
-                                        is_redirect = true;
-                                        continue;
-                                    }
-                                }
-                                _ => (),
-                            }
-                            buf.clear();
-                        }
-                        if page_title.is_empty()
-                            || page_txt.is_empty()
-                            || page_title.contains("Template:")
-                            || page_title.contains("Wikipedia:")
-                            || page_title.contains("File:")
-                            || page_title.contains("WP:")
-                            || page_title.contains("User:")
-                            || page_title.contains("Help:")
-                            || page_title.contains("Draft:")
-                            || page_title.len() > 255
-                            || page_title.len() == 1 //Skipping single characters as these are commonly complex symbols that mess up the adjacency list
-                            || page_title.contains("(disambiguation)")
-                            || page_txt.contains("{{disambiguation}}")
-                            || page_txt.contains("{{disambig")
-                            || page_title.contains("MOS:")
-                            || page_title.contains("module:")
-                            || page_title.contains("Module:")
-                            || page_title.contains("MediaWiki:")
-                            || page_title.contains("mediawiki:")
-                            ||page_title.contains("main page/")
-                        {
-                            continue;
-                        }
-                        let sanitized_page_title = sanitize_string(&page_title);
-                        if is_redirect {
-                            let links = self.link_handler.extract_links(page_txt);
-                            if links.is_empty() {
-                                continue;
-                            }
-                            let sanitized_redirect_output = sanitize_string(&links[0]);
-                            let redirect_entry = RedirectEntry {
-                                redirect_from: sanitized_page_title,
-                                redirect_to: sanitized_redirect_output,
-                            };
-                            self.database_handler
-                                .add_redirect_entry(&redirect_entry)
-                                .unwrap();
-                            continue;
-                        }
+            }
+        }
+    }
 
-                        let links = self.link_handler.extract_links(page_txt);
-                        if links.is_empty() {
-                            continue;
-                        }
-                        let curr_length = self.compute_length(links.len());
-                        let prev_prev_offset = prev_offset;
-                        prev_offset = self.compute_byte_offset(prev_offset, prev_length);
-
-                        let lookup_entry = LookupEntry {
-                            title: sanitized_page_title,
-                            byteoffset: prev_offset.try_into().unwrap(), // in bytes
-                            length: curr_length.try_into().unwrap(),
-                        };
-                        match self.database_handler.add_lookup_entry(&lookup_entry) {
-                            Ok(_) => {
-                                self.adj_list_handler
-                                    .add_to_adj_list(&prev_offset.to_string(), links.len(), links)
-                                    .unwrap();
-                                bar.inc(1);
-                                prev_length = curr_length;
-                                count += 1;
-                            }
-                            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-                                prev_offset = prev_prev_offset;
-                                continue;
-                                //keep going if we encounter a duplicate key error, but do not add to adj_list
-                            }
-                            Err(e) => panic!("error: {}", e), //propogate any other errors
-                        }
+    //Same output as pre_process_file, but for a pages-articles-multistream dump + its index.
+    //Each bz2 stream (~100 pages) is decompressed and parsed on a worker thread, the results are
+    //then recorded in stream order so the byte offsets come out exactly as in the single threaded pass.
+    pub fn pre_process_multistream(
+        &mut self,
+        dump_path: &Path,
+        index_path: &Path,
+    ) -> Result<(), WikigraphError>
+    where
+        L: Sync,
+    {
+        let (mut state, skip) = self.start_pre_process(true)?;
+        let streams = multistream::read_stream_ranges(dump_path, index_path)?;
+        self.read_multistream_siteinfo(dump_path, &streams)?;
+        let done = streams.partition_point(|stream| stream.start < skip);
+        let bar = multistream_progress_bar(&streams);
+        bar.set_position(skip);
+        let batch_size = rayon::current_num_threads() * STREAMS_PER_THREAD;
+
+        for batch in streams[done..].chunks(batch_size) {
+            let parsed: Vec<Result<Vec<PreparedPage>, WikigraphError>> = batch
+                .par_iter()
+                .map(|stream| {
+                    let xml = multistream::read_stream(dump_path, stream)?;
+                    //the position is the offset of the compressed stream, that is what bzip2recover and the index work with
+                    prepare_stream(&self.link_handler, &self.namespaces, &self.profile, &xml)
+                        .map_err(|source| WikigraphError::Xml {
+                            position: stream.start,
+                            source,
+                        })
+                })
+                .collect();
+            for pages in parsed {
+                for page in pages? {
+                    self.record_page(page, &mut state)?;
+                }
+            }
+            //checkpoints only fall between batches, the streams of a batch are parsed all at once.
+            self.flush_pages(&mut state)?;
+            if let Some(last) = batch.last() {
+                self.write_checkpoint(&state, last.end, true)?;
+                bar.set_position(last.end);
+            }
+        }
+        self.finish_pre_process(&state)?;
+        bar.finish();
+        Ok(())
+    }
+
+    //The <siteinfo> is in the first stream of the dump, before the first indexed one.
+    fn read_multistream_siteinfo(
+        &mut self,
+        dump_path: &Path,
+        streams: &[StreamRange],
+    ) -> Result<(), WikigraphError> {
+        let Some(first) = streams.first().filter(|first| first.start > 0) else {
+            return Ok(());
+        };
+        let xml = multistream::read_stream(
+            dump_path,
+            &StreamRange {
+                start: 0,
+                end: first.start,
+            },
+        )?;
+        read_siteinfo(
+            &mut Reader::from_reader(xml.as_slice()),
+            &mut self.namespaces,
+        )
+        .map_err(|source| WikigraphError::Xml {
+            position: 0,
+            source,
+        })
+    }
+
+    fn input_progress_bar(&self) -> ProgressBar {
+        byte_progress_bar(
+            self.input_progress
+                .as_ref()
+                .map(|progress| progress.total()),
+        )
+    }
+
+    //`xml_position` is the position in the decompressed xml, used when the position in the file is not known.
+    fn input_position(&self, xml_position: u64) -> u64 {
+        self.input_progress
+            .as_ref()
+            .map_or(xml_position, |progress| progress.position())
+    }
+
+    //Picks up the state of a resumed run, or starts from an empty adjacency list.
+    //Also returns where in the input to continue from.
+    fn start_pre_process(
+        &mut self,
+        multistream: bool,
+    ) -> Result<(PreProcessState, u64), WikigraphError> {
+        match self.resume_from.take() {
+            Some(checkpoint) if checkpoint.multistream != multistream => {
+                Err(WikigraphError::Consistency(format!(
+                    "the checkpoint was written by a {} run, resume it with the same input",
+                    if checkpoint.multistream {
+                        "multistream"
+                    } else {
+                        "single stream"
                     }
+                )))
+            }
+            Some(checkpoint) => Ok((
+                PreProcessState::from_checkpoint(&checkpoint),
+                checkpoint.position,
+            )),
+            None => {
+                self.adj_list_handler.truncate(0)?;
+                if let Some(link_metadata) = &mut self.link_metadata {
+                    link_metadata.truncate(0)?;
                 }
-                // There are several other `Event`s we do not consider here
+                Ok((PreProcessState::new(), 0))
+            }
+        }
+    }
+
+    fn write_checkpoint(
+        &mut self,
+        state: &PreProcessState,
+        position: u64,
+        multistream: bool,
+    ) -> Result<(), WikigraphError> {
+        let Some(path) = &self.checkpoint_path else {
+            return Ok(());
+        };
+        Checkpoint {
+            position,
+            multistream,
+            prev_offset: state.prev_offset,
+            prev_length: state.prev_length,
+            count: state.count,
+            adj_list_len: self.adj_list_handler.position()?,
+            link_metadata_len: match &mut self.link_metadata {
+                Some(link_metadata) => Some(link_metadata.position()?),
+                None => None,
+            },
+            duplicate_links: state.removed_links.duplicate_links,
+            self_links: state.removed_links.self_links,
+        }
+        .write(path)
+    }
+
+    fn finish_pre_process(&mut self, state: &PreProcessState) -> Result<(), WikigraphError> {
+        self.set_count(state.count);
+        self.removed_links = state.removed_links;
+        self.relocated = false;
+        self.write_manifest()?;
+        if let Some(path) = &self.checkpoint_path {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
                 _ => (),
             }
-            // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
-            buf.clear();
         }
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<(), WikigraphError> {
+        match &self.manifest_path {
+            Some(path) => Manifest {
+                node_count: self.count,
+                link_size: self.graph_builder.link_size(),
+                relocated: self.relocated,
+                duplicate_links: self.removed_links.duplicate_links,
+                self_links: self.removed_links.self_links,
+            }
+            .write(path),
+            None => Ok(()),
+        }
+    }
+
+    //Queues one page for the lookup/redirect tables and the adjacency list, writing them out in batches.
+    fn record_page(
+        &mut self,
+        page: PreparedPage,
+        state: &mut PreProcessState,
+    ) -> Result<(), WikigraphError> {
+        match page {
+            PreparedPage::Redirect(redirect_entry) => state.pending_redirects.push(redirect_entry),
+            PreparedPage::Article {
+                title,
+                links,
+                lead_length,
+            } => state.pending_articles.push((title, links, lead_length)),
+        }
+        if state.pending_articles.len() + state.pending_redirects.len() >= INSERT_BATCH_SIZE {
+            self.flush_pages(state)?;
+        }
+        Ok(())
     }
+
+    //Writes out the queued pages, advancing the byte offsets.
+    //Byte offsets depend on every earlier node, so duplicate titles (already in the table or twice
+    //in this batch) are dropped before any offset is handed out instead of relying on the insert failing.
+    fn flush_pages(&mut self, state: &mut PreProcessState) -> Result<(), WikigraphError> {
+        if !state.pending_redirects.is_empty() {
+            self.database_handler
+                .add_redirect_entries(&state.pending_redirects)?;
+            state.pending_redirects.clear();
+        }
+        if state.pending_articles.is_empty() {
+            return Ok(());
+        }
+
+        let titles: Vec<String> = state
+            .pending_articles
+            .iter()
+            .map(|(title, _, _)| title.clone())
+            .collect();
+        let mut seen = self.database_handler.existing_titles(&titles)?;
+        let mut lookup_entries = Vec::with_capacity(titles.len());
+        let folding = self.link_folding();
+        for (title, links, lead_length) in state.pending_articles.drain(..) {
+            let mut removed = RemovedLinks::default();
+            let (targets, template_links, edge_data) =
+                adjacency_of(&title, &links, lead_length, folding, &mut removed);
+            //a page that only links itself has no links left, and pages without links are not nodes.
+            if targets.is_empty() {
+                continue;
+            }
+            if !seen.insert(title.clone()) {
+                //keep going if we encounter a duplicate title, but do not add to adj_list
+                continue;
+            }
+            state.removed_links += removed;
+            if let Some(link_metadata) = &mut self.link_metadata {
+                link_metadata.write(state.count as usize, &title, &links)?;
+            }
+            let links = targets;
+            let curr_length = self.compute_length(links.len());
+            let byte_offset = self.compute_byte_offset(state.prev_offset, state.prev_length);
+            self.adj_list_handler.add_to_adj_list(
+                byte_offset as u64,
+                links.len(),
+                links,
+                template_links,
+                edge_data,
+            )?;
+            lookup_entries.push(LookupEntry {
+                title,
+                byteoffset: byte_offset as i64, // in bytes
+                length: curr_length as i32,
+            });
+            state.prev_offset = byte_offset;
+            state.prev_length = curr_length;
+            state.count += 1;
+        }
+        self.database_handler.add_lookup_entries(&lookup_entries)?;
+        Ok(())
+    }
+    //Alternative to pre_process_file + create_graph that goes from the dump to the graph without the adjacency list.
+    //The links are kept in a binary scratch file at `scratch_path` until every page is known.
+    pub fn build_single_pass<R: BufRead>(
+        &mut self,
+        input: R,
+        scratch_path: PathBuf,
+    ) -> Result<(), WikigraphError> {
+        let mut single_pass = SinglePass::new(scratch_path, self.graph_builder.has_edge_data())?;
+        let folding = self.link_folding();
+        let mut removed_links = RemovedLinks::default();
+        if let Some(link_metadata) = &mut self.link_metadata {
+            link_metadata.truncate(0)?;
+        }
+        let mut file_reader = Reader::from_reader(input);
+        file_reader.trim_text(true);
+        let bar = self.input_progress_bar();
+
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            match read_next_page(&mut file_reader, &mut buf, &mut self.namespaces) {
+                Err(source) => {
+                    return Err(WikigraphError::Xml {
+                        position: file_reader.buffer_position() as u64,
+                        source,
+                    })
+                }
+                Ok(Some(page)) => {
+                    if let Some(page) =
+                        prepare_page(&self.link_handler, &self.namespaces, &self.profile, page)
+                    {
+                        add_single_pass_page(
+                            &mut single_pass,
+                            self.link_metadata.as_mut(),
+                            folding,
+                            &mut removed_links,
+                            page,
+                        )?;
+                    }
+                    bar.set_position(self.input_position(file_reader.buffer_position() as u64));
+                }
+                Ok(None) => break,
+            }
+        }
+        bar.finish();
+        self.removed_links = removed_links;
+        self.finish_single_pass(single_pass)
+    }
+
+    //build_single_pass for a multistream dump, parsing the streams on all cores like pre_process_multistream.
+    pub fn build_single_pass_multistream(
+        &mut self,
+        dump_path: &Path,
+        index_path: &Path,
+        scratch_path: PathBuf,
+    ) -> Result<(), WikigraphError>
+    where
+        L: Sync,
+    {
+        let mut single_pass = SinglePass::new(scratch_path, self.graph_builder.has_edge_data())?;
+        let folding = self.link_folding();
+        let mut removed_links = RemovedLinks::default();
+        if let Some(link_metadata) = &mut self.link_metadata {
+            link_metadata.truncate(0)?;
+        }
+        let streams = multistream::read_stream_ranges(dump_path, index_path)?;
+        self.read_multistream_siteinfo(dump_path, &streams)?;
+        let bar = multistream_progress_bar(&streams);
+        let batch_size = rayon::current_num_threads() * STREAMS_PER_THREAD;
+
+        for batch in streams.chunks(batch_size) {
+            let parsed: Vec<Result<Vec<PreparedPage>, WikigraphError>> = batch
+                .par_iter()
+                .map(|stream| {
+                    let xml = multistream::read_stream(dump_path, stream)?;
+                    prepare_stream(&self.link_handler, &self.namespaces, &self.profile, &xml)
+                        .map_err(|source| WikigraphError::Xml {
+                            position: stream.start,
+                            source,
+                        })
+                })
+                .collect();
+            for pages in parsed {
+                for page in pages? {
+                    add_single_pass_page(
+                        &mut single_pass,
+                        self.link_metadata.as_mut(),
+                        folding,
+                        &mut removed_links,
+                        page,
+                    )?;
+                }
+            }
+            if let Some(last) = batch.last() {
+                bar.set_position(last.end);
+            }
+        }
+        bar.finish();
+        self.removed_links = removed_links;
+        self.finish_single_pass(single_pass)
+    }
+
+    fn finish_single_pass(&mut self, single_pass: SinglePass) -> Result<(), WikigraphError> {
+        println!("Parsed {} pages, writing the graph...", single_pass.count());
+        self.set_count(single_pass.count());
+        let folding = self.link_folding();
+        single_pass.write(
+            &mut self.graph_builder,
+            &mut self.database_handler,
+            self.dangling_links,
+            folding,
+            &mut self.resolved_removed_links,
+            self.dangling_report_path.as_deref(),
+        )
+    }
+
     //Second pass to take adjacency list + lookup table -> graph in binary format.
-    pub fn create_graph(&mut self) {
+    pub fn create_graph(&mut self) -> Result<(), WikigraphError> {
         //load database into memory (~2.5gbs)
-        const FILE_VERSION: i32 = 1;
         println!("loading into memory...");
         let start = std::time::Instant::now();
-        let mut map: HashMap<String, i32> = HashMap::new();
-        for (title, bytes) in self.database_handler.read_offsets_into_memory().iter() {
+        let mut lookup_entries = self.database_handler.read_offsets_into_memory()?;
+        //sorted by byte offset, the i-th entry is the i-th line of the adjacency list.
+        lookup_entries.sort_unstable_by_key(|(_, byte_offset)| *byte_offset);
+        let mut map: HashMap<String, i64> = HashMap::new();
+        for (title, bytes) in lookup_entries.iter() {
             map.insert(title.to_owned(), bytes.to_owned());
         }
+        let redirects: HashMap<String, String> = self
+            .database_handler
+            .read_redirects_into_memory()?
+            .into_iter()
+            .collect();
+        let (resolved, broken) = resolve_redirects(&mut map, &redirects);
         println!("Loaded into memory in {:?}", start.elapsed());
-
-        let bar = ProgressBar::new(NUM_ARTICLES);
-        bar.set_style(
-            ProgressStyle::with_template(
-                "[{wide_bar:.cyan/blue}] [{elapsed_precise}] {pos:>7}/{len:7} ({eta})",
-            )
-            .unwrap()
-            .with_key("eta", |state: &ProgressState, w: &mut dyn fmtWrite| {
-                write!(w, "{:.1}hrs", state.eta().as_secs_f64() / 3600.0).unwrap()
-            })
-            .progress_chars("#>-"),
+        println!(
+            "Resolved {} redirects, {} point at missing pages or loop",
+            resolved, broken
         );
 
-        self.graph_builder.write_file_header();
-        let mut count = 0;
+        //dropping links shrinks nodes, so every node after the first dangling link moves.
+        let (layout, moved) = self.lay_out_nodes(&map, &lookup_entries)?;
+        self.relocate_nodes(&mut map, &lookup_entries, &layout, moved)?;
 
-        for line in self.adj_list_handler.iter() {
-            match line {
-                Ok(line) => {
-                    count += 1;
-                    let mut split = line.split('|');
-                    let t = split.next().unwrap();
-                    let current_position = self.graph_builder.get_current_position();
-                    let expected_offset = t.parse::<u64>().unwrap();
-                    if current_position != expected_offset {
-                        panic!(
-                            "{} Byteoffset mismatch. expected: {}, got: {}, on line:{}, ",
-                            (expected_offset - current_position),
-                            expected_offset,
-                            current_position,
-                            count
-                        );
-                    }
-                    let num_links: i32 = split.next().unwrap().parse().unwrap();
-                    self.graph_builder.write_node_header(num_links);
-                    for link in split {
-                        if link.is_empty() {
-                            panic!("empty link for line {}", count);
-                        }
+        let bar = progress_bar(self.count as u64);
 
-                        let byte_offset = map.get(link).unwrap_or(&0).to_owned();
+        self.graph_builder.set_count(self.count);
+        self.graph_builder.write_file_header()?;
+        let mut count = 0;
+        let mut dangling: HashMap<String, usize> = HashMap::new();
+        let mut targets = Vec::new();
+        let folding = self.link_folding();
+        let mut removed_links = RemovedLinks::default();
 
-                        self.graph_builder.write_value(byte_offset);
+        for record in self.adj_list_handler.iter()? {
+            let AdjacencyRecord {
+                offset,
+                links,
+                template_links,
+                edge_data,
+            } = record?;
+            count += 1;
+            let expected_offset = match layout.get(count - 1) {
+                Some(entry) => entry.byteoffset as u64,
+                None => {
+                    return Err(WikigraphError::Consistency(format!(
+                        "the adjacency list changed while the graph was built, line {} at byte offset {} is new",
+                        count, offset
+                    )))
+                }
+            };
+            let current_position = self.graph_builder.get_current_position()?;
+            if current_position != expected_offset {
+                return Err(WikigraphError::Consistency(format!(
+                    "byte offset mismatch on adjacency list line {}. expected: {}, got: {}. \
+                    Was the adjacency list written by a different run than the one that filled the database, or for another graph version?",
+                    count, expected_offset, current_position
+                )));
+            }
+
+            if self.graph_builder.has_edge_data() && edge_data.len() != links.len() {
+                return Err(WikigraphError::Consistency(format!(
+                    "adjacency list line {} has no edge data, rerun preprocess for a graph version with edge data",
+                    count
+                )));
+            }
+            targets.clear();
+            let mut first_template_link = links.len() - template_links;
+            for (index, link) in links.iter().enumerate() {
+                let target = match map.get(link) {
+                    Some(&byte_offset) => byte_offset,
+                    None => {
+                        *dangling.entry(link.to_owned()).or_insert(0) += 1;
+                        match self.dangling_links {
+                            DanglingLinks::Drop => {
+                                if index < first_template_link {
+                                    first_template_link -= 1;
+                                }
+                                continue;
+                            }
+                            DanglingLinks::Sentinel => DANGLING_LINK,
+                            DanglingLinks::Fail => {
+                                return Err(WikigraphError::Consistency(format!(
+                                    "adjacency list line {} links to \"{}\", which is not a page or a redirect to one",
+                                    count, link
+                                )))
+                            }
+                        }
                     }
-                    //uncomment for easier debugging
-                    // if link_count != num_links {
-                    //     println!(
-                    //         "Link count mismatch. expected: {}, got: {}, on line:{} with {} empty links",
-                    //         num_links, link_count, count, empty_count
-                    //     );
-                    // }
+                };
+                targets.push((target, edge_data.get(index).copied()));
+            }
+            let template_targets = fold_resolved_links(
+                expected_offset as i64,
+                DANGLING_LINK,
+                &mut targets,
+                first_template_link,
+                folding,
+                &mut removed_links,
+            );
+            self.graph_builder
+                .write_node_header(targets.len() as i32, template_targets as i32)?;
+            for &(byte_offset, edge_data) in targets.iter() {
+                self.graph_builder.write_value(byte_offset)?;
+                if let Some(edge_data) = edge_data {
+                    self.graph_builder.write_edge_data(edge_data)?;
                 }
-                Err(e) => panic!("Error reading line: {:?}", e),
             }
             bar.inc(1);
         }
         bar.finish();
+        self.graph_builder.flush_writer()?;
+        self.resolved_removed_links = removed_links;
+
+        println!(
+            "{} links point at {} missing pages",
+            dangling.values().sum::<usize>(),
+            dangling.len()
+        );
+        if let Some(path) = &self.dangling_report_path {
+            write_dangling_report(path, dangling)?;
+        }
+        Ok(())
+    }
+
+    //Computes where every node ends up once the links that are left out of the graph are dropped: dangling links
+    //with --dangling-links drop, and the links that turn out to be repeated or self-links once redirects are resolved.
+    //Nodes are matched to lookup entries by their order, so this also works on a table that was relocated before.
+    //Unless the manifest says so, the lookup table has to be at the byte offsets of the adjacency list.
+    //Also returns whether any node moved away from its offset in the adjacency list.
+    fn lay_out_nodes(
+        &mut self,
+        map: &HashMap<String, i64>,
+        lookup_entries: &[(String, i64)],
+    ) -> Result<(Vec<LookupEntry>, bool), WikigraphError> {
+        let mut layout = Vec::with_capacity(lookup_entries.len());
+        let mut moved = false;
+        let folding = self.link_folding();
+        let mut targets = Vec::new();
+        let mut new_offset = FILE_HEADER_SIZE;
+        for (count, record) in self.adj_list_handler.iter()?.enumerate() {
+            let record = record?;
+            let Some((title, lookup_offset)) = lookup_entries.get(count) else {
+                return Err(WikigraphError::Consistency(format!(
+                    "the adjacency list has more lines than the lookup table has entries ({})",
+                    lookup_entries.len()
+                )));
+            };
+            if !self.relocated && *lookup_offset as u64 != record.offset {
+                return Err(WikigraphError::Consistency(format!(
+                    "adjacency list line {} is at byte offset {} but the lookup table does not have a page there. \
+                    Was the adjacency list written by a different run than the one that filled the database? \
+                    If an earlier build-graph moved the lookup table, leave out --node-count so its manifest is read.",
+                    count + 1,
+                    record.offset
+                )));
+            }
+            let mut first_template_link = record.links.len() - record.template_links;
+            targets.clear();
+            for (index, link) in record.links.iter().enumerate() {
+                match (map.get(link), self.dangling_links) {
+                    (Some(&target), _) => targets.push((target, None)),
+                    (None, DanglingLinks::Drop) => {
+                        if index < first_template_link {
+                            first_template_link -= 1;
+                        }
+                    }
+                    (None, _) => targets.push((DANGLING_LINK, None)),
+                }
+            }
+            fold_resolved_links(
+                *lookup_offset,
+                DANGLING_LINK,
+                &mut targets,
+                first_template_link,
+                folding,
+                &mut RemovedLinks::default(),
+            );
+            let num_links = targets.len();
+            moved |= new_offset as u64 != record.offset;
+            layout.push(LookupEntry {
+                title: title.clone(),
+                byteoffset: new_offset as i64,
+                length: self.compute_length(num_links) as i32,
+            });
+            new_offset += self.compute_length(num_links);
+        }
+        if layout.len() != lookup_entries.len() {
+            return Err(WikigraphError::Consistency(format!(
+                "the adjacency list has {} lines but the lookup table has {} entries",
+                layout.len(),
+                lookup_entries.len()
+            )));
+        }
+        Ok((layout, moved))
+    }
+
+    //Moves the lookup table, and the offsets in `map`, to `layout` if it is not there yet. `moved` is whether
+    //`layout` differs from the adjacency list.
+    //The manifest is marked as relocated before the table is touched, so a run that dies halfway through is not
+    //mistaken for a table at the offsets of the adjacency list.
+    fn relocate_nodes(
+        &mut self,
+        map: &mut HashMap<String, i64>,
+        lookup_entries: &[(String, i64)],
+        layout: &[LookupEntry],
+        moved: bool,
+    ) -> Result<(), WikigraphError> {
+        if lookup_entries
+            .iter()
+            .zip(layout)
+            .all(|((_, old_offset), entry)| *old_offset == entry.byteoffset)
+        {
+            return Ok(());
+        }
+        let relocated: HashMap<i64, i64> = lookup_entries
+            .iter()
+            .zip(layout)
+            .map(|((_, old_offset), entry)| (*old_offset, entry.byteoffset))
+            .collect();
+        for byte_offset in map.values_mut() {
+            *byte_offset = relocated[byte_offset];
+        }
+        self.relocated = true;
+        self.write_manifest()?;
+        self.database_handler.replace_lookup_entries(layout)?;
+        //false when it is back at the offsets of the first pass, ie: a sentinel build after a drop build.
+        self.relocated = moved;
+        self.write_manifest()
+    }
+
+    fn link_folding(&self) -> LinkFolding {
+        if self.graph_builder.has_edge_data() {
+            LinkFolding::Count
+        } else if self.keep_duplicate_links {
+            LinkFolding::Keep
+        } else {
+            LinkFolding::Drop
+        }
     }
 
     fn compute_byte_offset(&self, prev_offset: usize, prev_length: usize) -> usize {
@@ -290,6 +878,495 @@ impl Parser {
     }
 
     fn compute_length(&self, num_links: usize) -> usize {
-        NODE_HEADER_SIZE + num_links * LINK_SIZE
+        NODE_HEADER_SIZE + num_links * self.graph_builder.link_size()
+    }
+}
+
+//What to write for a link whose target is not in the graph (a missing page, or a redirect that goes nowhere).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DanglingLinks {
+    //Leave the link out. Nodes shrink, so the lookup table gets rewritten with the new byte offsets.
+    Drop,
+    //Keep the link as DANGLING_LINK, so byte offsets stay the same as after the first pass.
+    Sentinel,
+    //Stop building the graph at the first dangling link.
+    Fail,
+}
+
+impl FromStr for DanglingLinks {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "drop" => Ok(DanglingLinks::Drop),
+            "sentinel" => Ok(DanglingLinks::Sentinel),
+            "fail" => Ok(DanglingLinks::Fail),
+            _ => Err(format!(
+                "unknown dangling link policy {}, expected drop, sentinel or fail",
+                input
+            )),
+        }
+    }
+}
+
+//Links that are not written to the graph. The first pass leaves them out of the adjacency list (or the single pass
+//scratch file) by title, the graph is built without the ones that only show up once redirects are resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemovedLinks {
+    //Links to a target the page already links to. Version 4 graphs count them instead.
+    pub duplicate_links: u64,
+    //Links to the page itself, or to a redirect to it.
+    pub self_links: u64,
+}
+
+impl AddAssign for RemovedLinks {
+    fn add_assign(&mut self, other: RemovedLinks) {
+        self.duplicate_links += other.duplicate_links;
+        self.self_links += other.self_links;
+    }
+}
+
+//What happens to repeated links to the same target on a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LinkFolding {
+    //All of them are written.
+    Keep,
+    //Only the first one is written.
+    Drop,
+    //The first one is written with an EdgeData that counts them.
+    Count,
+}
+
+//Offsets bookkeeping for the first pass.
+struct PreProcessState {
+    prev_offset: usize,
+    prev_length: usize,
+    count: i32,
+    removed_links: RemovedLinks,
+    //title, links and lead section length of every article.
+    pending_articles: Vec<(String, Vec<Link>, usize)>,
+    pending_redirects: Vec<RedirectEntry>,
+}
+
+impl PreProcessState {
+    fn new() -> Self {
+        PreProcessState {
+            prev_offset: FILE_HEADER_SIZE,
+            prev_length: 0,
+            count: 0,
+            removed_links: RemovedLinks::default(),
+            pending_articles: Vec::new(),
+            pending_redirects: Vec::new(),
+        }
+    }
+
+    fn from_checkpoint(checkpoint: &Checkpoint) -> Self {
+        PreProcessState {
+            prev_offset: checkpoint.prev_offset,
+            prev_length: checkpoint.prev_length,
+            count: checkpoint.count,
+            removed_links: RemovedLinks {
+                duplicate_links: checkpoint.duplicate_links,
+                self_links: checkpoint.self_links,
+            },
+            pending_articles: Vec::new(),
+            pending_redirects: Vec::new(),
+        }
+    }
+
+    //True right after a flush, when everything read so far is in the database and the adjacency list.
+    fn is_flushed(&self) -> bool {
+        self.pending_articles.is_empty() && self.pending_redirects.is_empty()
+    }
+}
+
+//Raw contents of a <page> element.
+struct Page {
+    title: String,
+    //None for dumps without <ns> elements.
+    ns: Option<i32>,
+    text: String,
+    is_redirect: bool,
+}
+
+//A page that survived filtering, with its links already extracted.
+enum PreparedPage {
+    Redirect(RedirectEntry),
+    //Links from templates come last. The lead section is the first `lead_length` bytes of the page's text.
+    Article {
+        title: String,
+        links: Vec<Link>,
+        lead_length: usize,
+    },
+}
+
+//Reads up to and including the next </page>. Returns None at the end of the input.
+//The namespaces of a <siteinfo> passed on the way are added to `namespaces`.
+fn read_next_page<R: BufRead>(
+    file_reader: &mut Reader<R>,
+    buf: &mut Vec<u8>,
+    namespaces: &mut Namespaces,
+) -> Result<Option<Page>, quick_xml::Error> {
+    loop {
+        buf.clear();
+        match file_reader.read_event_into(buf)? {
+            Event::Eof => return Ok(None),
+            Event::Start(e) if e.name().as_ref() == b"siteinfo" => {
+                read_namespaces(file_reader, buf, namespaces)?
+            }
+            Event::Start(e) if e.name().as_ref() == b"page" => break,
+            // There are several other `Event`s we do not consider here
+            _ => (),
+        }
+    }
+    let mut page = Page {
+        title: String::new(),
+        ns: None,
+        text: String::new(),
+        is_redirect: false,
+    };
+    loop {
+        buf.clear();
+        match file_reader.read_event_into(buf)? {
+            Event::Start(e) if e.name().as_ref() == b"title" => {
+                if let Event::Text(e) = file_reader.read_event_into(buf)? {
+                    page.title = e.unescape()?.into_owned();
+                }
+            }
+            Event::Start(e) if e.name().as_ref() == b"ns" => {
+                if let Event::Text(e) = file_reader.read_event_into(buf)? {
+                    page.ns = e.unescape()?.trim().parse().ok();
+                }
+            }
+            Event::Start(e) if e.name().as_ref() == b"text" => {
+                if let Event::Text(e) = file_reader.read_event_into(buf)? {
+                    page.text = e.unescape()?.into_owned();
+                }
+            }
+            Event::Empty(e) if e.name().as_ref() == b"redirect" => page.is_redirect = true,
+            //Reached </page> tag
+            Event::End(e) if e.name().as_ref() == b"page" => break,
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(Some(page))
+}
+
+//Filters out pages that should not become nodes and extracts the links of the ones that remain.
+fn prepare_page(
+    link_handler: &impl LinkHandler,
+    namespaces: &Namespaces,
+    profile: &WikiProfile,
+    page: Page,
+) -> Option<PreparedPage> {
+    let Page {
+        title: page_title,
+        ns,
+        text: page_txt,
+        is_redirect,
+    } = page;
+    let ns = ns.unwrap_or_else(|| namespaces.key_of(&page_title));
+    if page_title.is_empty()
+        || page_txt.is_empty()
+        || !namespaces.is_included(ns)
+        || page_title.len() > 255
+        || page_title.len() == 1 //Skipping single characters as these are commonly complex symbols that mess up the adjacency list
+        || profile.is_disambiguation_page(&page_title, &page_txt)
+        || profile.is_skipped_title(&page_title)
+    {
+        return None;
+    }
+    let normalized_page_title = normalize_title(&page_title);
+    let lead_length = lead_section_end(&page_txt);
+    let mut links = link_handler.extract_links(page_txt);
+    if is_redirect {
+        //a redirect into a namespace that is left out points nowhere, it is dropped as well.
+        let target = links
+            .iter()
+            .find(|link| !link.from_template)
+            .map(|link| &link.target)
+            .filter(|target| {
+                namespaces.includes_title(target) && !profile.is_disambiguation_title(target)
+            })?;
+        let normalized_redirect_output = normalize_title(target);
+        return Some(PreparedPage::Redirect(RedirectEntry {
+            redirect_from: normalized_page_title,
+            redirect_to: normalized_redirect_output,
+        }));
+    }
+    //[[Category:X]] and [[File:X]] are not links from the page, [[:Category:X]] is.
+    links.retain(|link| {
+        (link.leading_colon || !namespaces.is_inclusion(&link.target))
+            && namespaces.includes_title(&link.target)
+            && !profile.is_disambiguation_title(&link.target)
+    });
+    if links.is_empty() {
+        return None;
+    }
+    //template links go last, so a count is all it takes to tell them apart.
+    links.sort_by_key(|link| link.from_template);
+    Some(PreparedPage::Article {
+        title: normalized_page_title,
+        links,
+        lead_length,
+    })
+}
+
+//Parses every page of one decompressed multistream stream.
+fn prepare_stream(
+    link_handler: &impl LinkHandler,
+    namespaces: &Namespaces,
+    profile: &WikiProfile,
+    xml: &[u8],
+) -> Result<Vec<PreparedPage>, quick_xml::Error> {
+    let mut file_reader = Reader::from_reader(xml);
+    file_reader.trim_text(true);
+    let mut buf: Vec<u8> = Vec::new();
+    let mut pages = Vec::new();
+    //streams never hold a <siteinfo>, the namespaces were read from the first one beforehand.
+    let mut stream_namespaces = namespaces.clone();
+    while let Some(page) = read_next_page(&mut file_reader, &mut buf, &mut stream_namespaces)? {
+        if let Some(page) = prepare_page(link_handler, namespaces, profile, page) {
+            pages.push(page);
+        }
+    }
+    Ok(pages)
+}
+
+fn add_single_pass_page(
+    single_pass: &mut SinglePass,
+    link_metadata: Option<&mut LinkMetadataWriter>,
+    folding: LinkFolding,
+    removed_links: &mut RemovedLinks,
+    page: PreparedPage,
+) -> Result<(), WikigraphError> {
+    match page {
+        PreparedPage::Redirect(redirect_entry) => single_pass.add_redirect(&redirect_entry),
+        PreparedPage::Article {
+            title,
+            links,
+            lead_length,
+        } => {
+            //only counted once the page is known not to be a duplicate, like in flush_pages.
+            let mut removed = RemovedLinks::default();
+            let (targets, template_links, edge_data) =
+                adjacency_of(&title, &links, lead_length, folding, &mut removed);
+            if targets.is_empty() {
+                return Ok(());
+            }
+            if single_pass.add_article(&title, &targets, template_links, &edge_data)? {
+                removed_links.duplicate_links += removed.duplicate_links;
+                removed_links.self_links += removed.self_links;
+                if let Some(link_metadata) = link_metadata {
+                    link_metadata.write(single_pass.count() as usize - 1, &title, &links)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+//Targets of the links of `title` as they go into the graph, and how many of them come from templates.
+//Self-links are left out and repeated links are folded as `folding` says, in the order the targets first appear.
+//With LinkFolding::Count every target gets an EdgeData with how often it is linked and whether once in the lead
+//section. `links` has the template links last, so a target that is also linked from the text keeps the edge of the
+//text (and counts the template links with it), only targets that are linked from templates alone stay template links.
+fn adjacency_of(
+    title: &str,
+    links: &[Link],
+    lead_length: usize,
+    folding: LinkFolding,
+    removed_links: &mut RemovedLinks,
+) -> (Vec<String>, usize, Vec<EdgeData>) {
+    let mut folded: HashMap<&str, usize> = HashMap::new();
+    let mut targets = Vec::new();
+    let mut edge_data: Vec<EdgeData> = Vec::new();
+    let mut template_links = 0;
+    for link in links {
+        if link.target == title {
+            removed_links.self_links += 1;
+            continue;
+        }
+        let lead = link.position < lead_length;
+        if folding != LinkFolding::Keep {
+            if let Some(&index) = folded.get(link.target.as_str()) {
+                match folding {
+                    LinkFolding::Count => edge_data[index].merge(EdgeData {
+                        occurrences: 1,
+                        lead,
+                    }),
+                    _ => removed_links.duplicate_links += 1,
+                }
+                continue;
+            }
+            folded.insert(link.target.as_str(), targets.len());
+        }
+        targets.push(link.target.clone());
+        if folding == LinkFolding::Count {
+            edge_data.push(EdgeData {
+                occurrences: 1,
+                lead,
+            });
+        }
+        if link.from_template {
+            template_links += 1;
+        }
+    }
+    (targets, template_links, edge_data)
+}
+
+//Leaves out the links of `node` to itself and folds repeated targets as `folding` says, once redirects are resolved
+//and links to different titles can turn out to point at the same node. The edge data of folded links is merged.
+//`targets` has the template links last, from `first_template_link` on. Returns how many of them are left.
+//Links to `dangling` are not folded, they point at different missing pages.
+//A node can be left without links. It stays a node, other nodes may link to it already, like with dropped dangling links.
+pub(crate) fn fold_resolved_links<T: Copy + Eq + Hash>(
+    node: T,
+    dangling: T,
+    targets: &mut Vec<(T, Option<EdgeData>)>,
+    first_template_link: usize,
+    folding: LinkFolding,
+    removed_links: &mut RemovedLinks,
+) -> usize {
+    let mut folded: HashMap<T, usize> = HashMap::new();
+    let mut kept: Vec<(T, Option<EdgeData>)> = Vec::with_capacity(targets.len());
+    let mut template_links = 0;
+    for (index, &(target, edge_data)) in targets.iter().enumerate() {
+        if target == node {
+            removed_links.self_links += 1;
+            continue;
+        }
+        if folding != LinkFolding::Keep && target != dangling {
+            if let Some(&at) = folded.get(&target) {
+                match (&mut kept[at].1, edge_data) {
+                    (Some(kept_edge_data), Some(edge_data)) if folding == LinkFolding::Count => {
+                        kept_edge_data.merge(edge_data)
+                    }
+                    _ => removed_links.duplicate_links += 1,
+                }
+                continue;
+            }
+            folded.insert(target, kept.len());
+        }
+        kept.push((target, edge_data));
+        if index >= first_template_link {
+            template_links += 1;
+        }
+    }
+    *targets = kept;
+    template_links
+}
+
+//One `count<TAB>title` line per missing page, most linked first.
+pub(crate) fn write_dangling_report(
+    path: &Path,
+    dangling: HashMap<String, usize>,
+) -> Result<(), WikigraphError> {
+    let mut dangling: Vec<(String, usize)> = dangling.into_iter().collect();
+    dangling.sort_unstable_by(|(a_title, a_count), (b_title, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_title.cmp(b_title))
+    });
+    let mut report = BufWriter::new(File::create(path)?);
+    for (target, count) in dangling {
+        writeln!(report, "{}\t{}", count, target)?;
+    }
+    report.flush()?;
+    Ok(())
+}
+
+//Adds every redirect title to `offsets`, pointing at the page its redirect chain ends at.
+//Real pages win over redirects with the same title. Returns how many redirects were resolved and how many
+//could not be: their chain ends at a page that is not in the graph, or loops back on itself.
+fn resolve_redirects(
+    offsets: &mut HashMap<String, i64>,
+    redirects: &HashMap<String, String>,
+) -> (usize, usize) {
+    let mut resolved = 0;
+    let mut broken = 0;
+    for from in redirects.keys() {
+        if offsets.contains_key(from) {
+            continue;
+        }
+        let mut visited = HashSet::from([from.as_str()]);
+        let mut target = redirects[from].as_str();
+        let offset = loop {
+            if let Some(&offset) = offsets.get(target) {
+                break Some(offset);
+            }
+            match redirects.get(target) {
+                Some(next) if visited.insert(target) => target = next,
+                _ => break None,
+            }
+        };
+        match offset {
+            Some(offset) => {
+                offsets.insert(from.clone(), offset);
+                resolved += 1;
+            }
+            None => broken += 1,
+        }
+    }
+    (resolved, broken)
+}
+
+//Progress through the input in bytes, for the first pass. `len` is None if the size of the input is not known.
+fn byte_progress_bar(len: Option<u64>) -> ProgressBar {
+    let bar = ProgressBar::with_draw_target(len, ProgressDrawTarget::stderr());
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{wide_bar:.cyan/blue}] [{elapsed_precise}] {bytes:>10}/{total_bytes:10} ({eta})",
+        )
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn fmtWrite| {
+            write!(w, "{:.1}hrs", state.eta().as_secs_f64() / 3600.0).unwrap()
+        })
+        .progress_chars("#>-"),
+    );
+    bar
+}
+
+//The streams are parsed in order, so the end of the last finished one is how far into the dump file we are.
+fn multistream_progress_bar(streams: &[StreamRange]) -> ProgressBar {
+    byte_progress_bar(Some(streams.last().map_or(0, |last| last.end)))
+}
+
+pub(crate) fn progress_bar(len: u64) -> ProgressBar {
+    let bar = ProgressBar::new(len);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{wide_bar:.cyan/blue}] [{elapsed_precise}] {pos:>7}/{len:7} ({eta})",
+        )
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn fmtWrite| {
+            write!(w, "{:.1}hrs", state.eta().as_secs_f64() / 3600.0).unwrap()
+        })
+        .progress_chars("#>-"),
+    );
+    bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_resolved_links, LinkFolding, RemovedLinks};
+
+    #[test]
+    fn nodes_can_lose_every_link_to_redirects() {
+        //node 1 links 2 redirects to itself and 2 to node 3, the last one from a template
+        let mut targets = vec![(1, None), (3, None), (1, None), (3, None)];
+        let mut removed = RemovedLinks::default();
+        let template_links =
+            fold_resolved_links(1, -1, &mut targets, 3, LinkFolding::Drop, &mut removed);
+        assert_eq!(targets, [(3, None)]);
+        assert_eq!(template_links, 0);
+        assert_eq!(removed.self_links, 2);
+        assert_eq!(removed.duplicate_links, 1);
+
+        let mut targets = vec![(1, None), (1, None)];
+        let template_links =
+            fold_resolved_links(1, -1, &mut targets, 2, LinkFolding::Drop, &mut removed);
+        assert!(targets.is_empty());
+        assert_eq!(template_links, 0);
+        assert_eq!(removed.self_links, 4);
     }
 }
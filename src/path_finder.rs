@@ -0,0 +1,210 @@
+use crate::error::WikigraphError;
+use crate::graph_reader::{CsrGraphReader, GraphReader};
+
+const UNVISITED: u32 = u32::MAX;
+
+enum Graph<'a> {
+    //Version 1, 3 and 4 graphs, links are byte offsets.
+    Offsets(&'a GraphReader),
+    //Version 2 graphs, links already are dense node ids.
+    Csr(&'a CsrGraphReader),
+}
+
+//Bidirectional BFS over the binary graph.
+//Nodes are renumbered to dense indices so the visited/parent tables can be plain vectors,
+//and the incoming edges are built once up front since the file only stores outgoing links.
+pub struct PathFinder<'a> {
+    graph: Graph<'a>,
+    offsets: Vec<usize>,
+    incoming_starts: Vec<u32>,
+    incoming: Vec<u32>,
+}
+
+impl<'a> PathFinder<'a> {
+    pub fn new(graph: &'a GraphReader) -> Self {
+        let offsets: Vec<usize> = graph.nodes().map(|node| node.offset).collect();
+        PathFinder::build(Graph::Offsets(graph), offsets)
+    }
+
+    //A version 2 graph does not know the byte offsets of its nodes, node i is the i-th of `offsets`
+    //(the byteoffsets of the lookup table, sorted).
+    pub fn from_csr(
+        graph: &'a CsrGraphReader,
+        offsets: Vec<usize>,
+    ) -> Result<Self, WikigraphError> {
+        if offsets.len() != graph.num_nodes() as usize {
+            return Err(WikigraphError::Format(format!(
+                "the lookup table has {} pages but the graph has {} nodes, it was not built from this database",
+                offsets.len(),
+                graph.num_nodes()
+            )));
+        }
+        Ok(PathFinder::build(Graph::Csr(graph), offsets))
+    }
+
+    fn build(graph: Graph<'a>, offsets: Vec<usize>) -> Self {
+        let mut path_finder = PathFinder {
+            graph,
+            offsets,
+            incoming_starts: Vec::new(),
+            incoming: Vec::new(),
+        };
+
+        //counting pass, then a fill pass (CSR layout for the reverse edges)
+        let num_nodes = path_finder.offsets.len();
+        let mut incoming_starts = vec![0u32; num_nodes + 1];
+        for from in 0..num_nodes {
+            for to in path_finder.outgoing(from as u32) {
+                incoming_starts[to as usize + 1] += 1;
+            }
+        }
+        for i in 0..num_nodes {
+            incoming_starts[i + 1] += incoming_starts[i];
+        }
+        let mut fill = incoming_starts.clone();
+        let mut incoming = vec![0u32; incoming_starts[num_nodes] as usize];
+        for from in 0..num_nodes {
+            for to in path_finder.outgoing(from as u32) {
+                incoming[fill[to as usize] as usize] = from as u32;
+                fill[to as usize] += 1;
+            }
+        }
+        path_finder.incoming_starts = incoming_starts;
+        path_finder.incoming = incoming;
+        path_finder
+    }
+
+    //Returns the byte offsets of the nodes along a shortest path, including both ends.
+    pub fn shortest_path(&self, from_offset: usize, to_offset: usize) -> Option<Vec<usize>> {
+        let from = self.index_of(from_offset)?;
+        let to = self.index_of(to_offset)?;
+        if from == to {
+            return Some(vec![from_offset]);
+        }
+
+        let num_nodes = self.offsets.len();
+        let mut forward_parent = vec![UNVISITED; num_nodes];
+        let mut backward_parent = vec![UNVISITED; num_nodes];
+        let mut forward_dist = vec![UNVISITED; num_nodes];
+        let mut backward_dist = vec![UNVISITED; num_nodes];
+        forward_parent[from as usize] = from;
+        forward_dist[from as usize] = 0;
+        backward_parent[to as usize] = to;
+        backward_dist[to as usize] = 0;
+        let mut forward_frontier = vec![from];
+        let mut backward_frontier = vec![to];
+
+        while !forward_frontier.is_empty() && !backward_frontier.is_empty() {
+            //always grow the smaller side, this is what keeps bidirectional search cheap
+            let meeting = if forward_frontier.len() <= backward_frontier.len() {
+                self.expand_level(
+                    &mut forward_frontier,
+                    &mut forward_parent,
+                    &mut forward_dist,
+                    &backward_dist,
+                    |node| self.outgoing(node),
+                )
+            } else {
+                self.expand_level(
+                    &mut backward_frontier,
+                    &mut backward_parent,
+                    &mut backward_dist,
+                    &forward_dist,
+                    |node| self.incoming(node),
+                )
+            };
+            if let Some(meeting) = meeting {
+                return Some(self.build_path(meeting, &forward_parent, &backward_parent));
+            }
+        }
+        None
+    }
+
+    //Expands one whole BFS level. Every meeting point found in the level is compared
+    //so the returned one is on a shortest path.
+    fn expand_level<I: Iterator<Item = u32>>(
+        &self,
+        frontier: &mut Vec<u32>,
+        parent: &mut [u32],
+        dist: &mut [u32],
+        other_dist: &[u32],
+        neighbours: impl Fn(u32) -> I,
+    ) -> Option<u32> {
+        let mut next_frontier = Vec::new();
+        let mut best: Option<(u32, u32)> = None;
+        for &node in frontier.iter() {
+            for next in neighbours(node) {
+                if dist[next as usize] != UNVISITED {
+                    continue;
+                }
+                parent[next as usize] = node;
+                dist[next as usize] = dist[node as usize] + 1;
+                if other_dist[next as usize] != UNVISITED {
+                    let total = dist[next as usize] + other_dist[next as usize];
+                    if best.is_none_or(|(_, best_total)| total < best_total) {
+                        best = Some((next, total));
+                    }
+                }
+                next_frontier.push(next);
+            }
+        }
+        *frontier = next_frontier;
+        best.map(|(node, _)| node)
+    }
+
+    fn build_path(
+        &self,
+        meeting: u32,
+        forward_parent: &[u32],
+        backward_parent: &[u32],
+    ) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut node = meeting;
+        while forward_parent[node as usize] != node {
+            node = forward_parent[node as usize];
+            path.push(node);
+        }
+        path.reverse();
+        let mut node = meeting;
+        path.push(node);
+        while backward_parent[node as usize] != node {
+            node = backward_parent[node as usize];
+            path.push(node);
+        }
+        path.into_iter()
+            .map(|index| self.offsets[index as usize])
+            .collect()
+    }
+
+    fn index_of(&self, offset: usize) -> Option<u32> {
+        self.offsets
+            .binary_search(&offset)
+            .ok()
+            .map(|index| index as u32)
+    }
+
+    //Links that do not point at a node (ie: unresolved links written as 0, or CSR_DANGLING_LINK) are skipped.
+    fn outgoing(&self, node: u32) -> impl Iterator<Item = u32> + '_ {
+        let (offset_links, csr_links) = match self.graph {
+            Graph::Offsets(graph) => (graph.links_of(self.offsets[node as usize]), None),
+            Graph::Csr(graph) => (None, graph.links_of(node)),
+        };
+        let num_nodes = self.offsets.len() as u32;
+        offset_links
+            .into_iter()
+            .flatten()
+            .filter_map(|link| self.index_of(usize::try_from(link).ok()?))
+            .chain(
+                csr_links
+                    .into_iter()
+                    .flatten()
+                    .filter(move |&link| link < num_nodes),
+            )
+    }
+
+    fn incoming(&self, node: u32) -> impl Iterator<Item = u32> + '_ {
+        let start = self.incoming_starts[node as usize] as usize;
+        let end = self.incoming_starts[node as usize + 1] as usize;
+        self.incoming[start..end].iter().copied()
+    }
+}
@@ -0,0 +1,219 @@
+use crate::error::WikigraphError;
+use crate::wikitext::{self, Kind, Node};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+//What differs between the language editions of Wikipedia, loaded from a TOML file (see profiles/).
+//The namespace names themselves are read from the dump, only aliases that are not listed there go here.
+//Fields missing from the file keep their English default.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WikiProfile {
+    //Pages with one of these in their title are disambiguation pages. They are skipped and so are links to them.
+    pub disambiguation_title_markers: Vec<String>,
+    //Pages using one of these templates are disambiguation pages. Names are matched like MediaWiki does, ignoring
+    //the case of the first letter and `_`. A name ending in `*` matches every template that starts with the rest.
+    pub disambiguation_templates: Vec<String>,
+    //Pages with one of these in their title are skipped.
+    pub skipped_title_markers: Vec<String>,
+    //Other names for namespaces, by key (ie: WP = 4).
+    pub namespace_aliases: HashMap<String, i32>,
+    //Templates whose links are extracted with --template-links.
+    pub link_templates: Vec<LinkTemplate>,
+}
+
+//A template that links to other pages, ie: {{Main|Article}} or a navbox.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkTemplate {
+    //Matched like disambiguation_templates: ignoring the case of the first letter and `_`. A name ending in `*`
+    //matches every template that starts with the rest.
+    pub name: String,
+    //The unnamed parameters are page titles, like in {{Main|Article}}.
+    #[serde(default)]
+    pub titles: bool,
+    //Named parameters whose [[links]] are kept, "*" for all of them.
+    #[serde(default)]
+    pub parameters: Vec<String>,
+}
+
+impl LinkTemplate {
+    fn new(name: &str, titles: bool, parameters: &[&str]) -> Self {
+        LinkTemplate {
+            name: name.to_owned(),
+            titles,
+            parameters: parameters.iter().map(|&p| p.to_owned()).collect(),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        template_name_matches(&self.name, name)
+    }
+
+    pub fn keeps_parameter(&self, name: &str) -> bool {
+        let name = name.trim();
+        self.parameters
+            .iter()
+            .any(|parameter| parameter == "*" || parameter.trim() == name)
+    }
+}
+
+impl Default for WikiProfile {
+    fn default() -> Self {
+        WikiProfile {
+            disambiguation_title_markers: vec!["(disambiguation)".to_owned()],
+            disambiguation_templates: [
+                "Disambiguation",
+                "Disambig",
+                "Disamb",
+                "Dab",
+                "Geodis",
+                "Hndis",
+                "Human name disambiguation",
+            ]
+            .iter()
+            .map(|&name| name.to_owned())
+            .collect(),
+            skipped_title_markers: vec!["main page/".to_owned()],
+            namespace_aliases: HashMap::new(),
+            link_templates: vec![
+                LinkTemplate::new("Main", true, &[]),
+                LinkTemplate::new("See also", true, &[]),
+                LinkTemplate::new("Further", true, &[]),
+                LinkTemplate::new("Details", true, &[]),
+                LinkTemplate::new("Navbox*", false, &["*"]),
+                LinkTemplate::new("Infobox*", false, &["*"]),
+            ],
+        }
+    }
+}
+
+impl WikiProfile {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, WikigraphError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| {
+            WikigraphError::Format(format!("invalid profile {}: {}", path.display(), e))
+        })
+    }
+
+    pub fn is_disambiguation_title(&self, title: &str) -> bool {
+        self.disambiguation_title_markers
+            .iter()
+            .any(|marker| title.contains(marker.as_str()))
+    }
+
+    pub fn is_disambiguation_page(&self, title: &str, text: &str) -> bool {
+        if self.is_disambiguation_title(title) {
+            return true;
+        }
+        let is_disambiguation_template = |name: &str| {
+            self.disambiguation_templates
+                .iter()
+                .any(|template| template_name_matches(template, name))
+        };
+        //most pages call none of the templates, their text is only parsed to rule out calls in comments, <nowiki>...
+        template_calls(text).any(is_disambiguation_template)
+            && uses_template(&wikitext::parse(text), &is_disambiguation_template)
+    }
+
+    pub fn is_skipped_title(&self, title: &str) -> bool {
+        self.skipped_title_markers
+            .iter()
+            .any(|marker| title.contains(marker.as_str()))
+    }
+}
+
+//Whether one of `nodes`, or a template nested in them, is a template whose name `matches`.
+fn uses_template(nodes: &[Node], matches: &impl Fn(&str) -> bool) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Element(element) => {
+            (element.kind == Kind::Template
+                && element.template_name().is_some_and(|name| matches(&name)))
+                || uses_template(&element.children, matches)
+        }
+        _ => false,
+    })
+}
+
+//Whether a call of the template `name` is a call of `template`, which ends in `*` to match every name starting with it.
+fn template_name_matches(template: &str, name: &str) -> bool {
+    let name = normalize_template_name(name);
+    match template.strip_suffix('*') {
+        Some(prefix) => name.starts_with(&normalize_template_name(prefix)),
+        None => name == normalize_template_name(template),
+    }
+}
+
+//Template names are case sensitive except for their first letter, like page titles.
+fn normalize_template_name(name: &str) -> String {
+    let name = name.replace('_', " ");
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+//What follows every `{{` of `text` up to the first `|` or `}`: the name of every template the page calls, and more.
+fn template_calls(text: &str) -> impl Iterator<Item = &str> {
+    text.split("{{")
+        .skip(1)
+        .map(|call| &call[..call.find(['|', '}']).unwrap_or(call.len())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{template_calls, WikiProfile};
+
+    #[test]
+    fn matches_disambiguation_templates_by_name() {
+        let profile = WikiProfile::default();
+        let is_disambiguation = |text: &str| profile.is_disambiguation_page("Mercury", text);
+        assert!(is_disambiguation(
+            "'''Mercury''' may be:\n{{Disambiguation}}"
+        ));
+        assert!(is_disambiguation("{{ disambig}}"));
+        assert!(is_disambiguation("{{dab|geo}}"));
+        assert!(is_disambiguation("{{Human_name  disambiguation}}"));
+        assert!(!is_disambiguation(
+            "[[Mercury]] {{disambiguation needed|date=May 2024}}"
+        ));
+        assert!(!is_disambiguation("{{DISAMBIGUATION}}"));
+        assert!(!is_disambiguation("<nowiki>{{Disambiguation}}</nowiki>"));
+        assert!(profile.is_disambiguation_page("Mercury (disambiguation)", "[[Mercury]]"));
+    }
+
+    #[test]
+    fn only_template_calls_are_candidates() {
+        let calls: Vec<_> =
+            template_calls("A [[Dab]]ble {{Infobox|x={{dab}}}} {{ Human name\ndisambiguation }}")
+                .collect();
+        assert_eq!(calls, ["Infobox", "dab", " Human name\ndisambiguation "]);
+        assert_eq!(
+            template_calls("no templates, just a dab of text").count(),
+            0
+        );
+    }
+
+    #[test]
+    fn matches_link_templates_like_disambiguation_templates() {
+        let profile = WikiProfile::default();
+        let is_link_template = |name: &str| profile.link_templates.iter().any(|t| t.matches(name));
+        assert!(is_link_template("main"));
+        assert!(is_link_template("See_also"));
+        assert!(is_link_template("navbox with columns"));
+        assert!(!is_link_template("MAIN"));
+        assert!(!is_link_template("see ALSO"));
+    }
+
+    #[test]
+    fn the_enwiki_profile_is_the_default() {
+        let profile =
+            WikiProfile::read(concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/enwiki.toml"));
+        assert_eq!(profile.unwrap(), WikiProfile::default());
+    }
+}
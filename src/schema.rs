@@ -4,7 +4,7 @@ diesel::table! {
     lookup (title) {
         #[max_length = 255]
         title -> Varchar,
-        byteoffset -> Int4,
+        byteoffset -> Int8,
         length -> Int4,
     }
 }
@@ -18,7 +18,4 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(
-    lookup,
-    redirect,
-);
+diesel::allow_tables_to_appear_in_same_query!(lookup, redirect,);
@@ -0,0 +1,316 @@
+use crate::database_handler::DatabaseHandler;
+use crate::error::WikigraphError;
+use crate::graph_builder::{
+    EdgeData, GraphBuilder, DANGLING_LINK, FILE_HEADER_SIZE, NODE_HEADER_SIZE,
+};
+use crate::interner::Interner;
+use crate::models::{LookupEntry, RedirectEntry};
+use crate::parser::{
+    fold_resolved_links, progress_bar, write_dangling_report, DanglingLinks, LinkFolding,
+    RemovedLinks,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const NO_NODE: u32 = u32::MAX;
+//Rows handed to the database at a time once the graph is written.
+const INSERT_BATCH_SIZE: usize = 100_000;
+
+//Builds the graph straight from the parsed pages, without the text adjacency list and the lookup table round trip.
+//Pages get dense node ids in the order they are parsed and link targets are interned. The links of each page go
+//to a binary scratch file as `num_links, num_template_links, target id...` (u32, little endian), followed by
+//`occurrences, flags` for every link if the graph has edge data. Once the whole dump is read every target can be
+//resolved, so the scratch file is streamed into the graph and the lookup table is written last.
+pub struct SinglePass {
+    titles: Interner,
+    //node of every interned title, NO_NODE for titles that are only linked to or redirects.
+    node_of: Vec<u32>,
+    //interned title of every node.
+    nodes: Vec<u32>,
+    redirects: HashMap<u32, u32>,
+    scratch: BufWriter<File>,
+    scratch_path: PathBuf,
+    has_edge_data: bool,
+}
+
+impl SinglePass {
+    //`has_edge_data` if the graph builder it is written with has edge data.
+    pub fn new(scratch_path: PathBuf, has_edge_data: bool) -> Result<Self, WikigraphError> {
+        let scratch = BufWriter::new(File::create(&scratch_path)?);
+        Ok(SinglePass {
+            titles: Interner::new(),
+            node_of: Vec::new(),
+            nodes: Vec::new(),
+            redirects: HashMap::new(),
+            scratch,
+            scratch_path,
+            has_edge_data,
+        })
+    }
+
+    pub fn count(&self) -> i32 {
+        self.nodes.len() as i32
+    }
+
+    //Returns false if a page with the same title was added before, the first one is kept like in the first pass.
+    //The last `template_links` of `links` come from templates. `edge_data` has one entry per link if has_edge_data.
+    pub fn add_article(
+        &mut self,
+        title: &str,
+        links: &[String],
+        template_links: usize,
+        edge_data: &[EdgeData],
+    ) -> Result<bool, WikigraphError> {
+        let id = self.intern(title);
+        if self.node_of[id as usize] != NO_NODE {
+            return Ok(false);
+        }
+        self.node_of[id as usize] = self.nodes.len() as u32;
+        self.nodes.push(id);
+        self.scratch.write_u32::<LittleEndian>(links.len() as u32)?;
+        self.scratch
+            .write_u32::<LittleEndian>(template_links as u32)?;
+        for link in links {
+            let target = self.intern(link);
+            self.scratch.write_u32::<LittleEndian>(target)?;
+        }
+        if self.has_edge_data {
+            for edge_data in edge_data {
+                self.scratch
+                    .write_u32::<LittleEndian>(edge_data.occurrences)?;
+                self.scratch.write_u32::<LittleEndian>(edge_data.flags())?;
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn add_redirect(&mut self, redirect_entry: &RedirectEntry) {
+        let from = self.intern(&redirect_entry.redirect_from);
+        let to = self.intern(&redirect_entry.redirect_to);
+        self.redirects.entry(from).or_insert(to);
+    }
+
+    fn intern(&mut self, title: &str) -> u32 {
+        let id = self.titles.intern(title);
+        if id as usize == self.node_of.len() {
+            self.node_of.push(NO_NODE);
+        }
+        id
+    }
+
+    //Node every interned title ends up at, following redirect chains like create_graph does.
+    fn resolve(&self) -> Vec<u32> {
+        let mut resolved = self.node_of.clone();
+        for (&from, &to) in self.redirects.iter() {
+            if resolved[from as usize] != NO_NODE {
+                continue;
+            }
+            let mut visited = HashSet::from([from]);
+            let mut target = to;
+            loop {
+                if self.node_of[target as usize] != NO_NODE {
+                    resolved[from as usize] = self.node_of[target as usize];
+                    break;
+                }
+                match self.redirects.get(&target) {
+                    Some(&next) if visited.insert(target) => target = next,
+                    _ => break,
+                }
+            }
+        }
+        resolved
+    }
+
+    //Writes the graph, then the lookup and redirect tables. The scratch file is removed afterwards.
+    //Links that turn out to be repeated or self-links once redirects are resolved are folded as `folding` says,
+    //and added to `removed_links`.
+    pub(crate) fn write<G: GraphBuilder, D: DatabaseHandler>(
+        mut self,
+        graph_builder: &mut G,
+        database_handler: &mut D,
+        dangling_links: DanglingLinks,
+        folding: LinkFolding,
+        removed_links: &mut RemovedLinks,
+        dangling_report_path: Option<&Path>,
+    ) -> Result<(), WikigraphError> {
+        self.scratch.flush()?;
+        let resolved = self.resolve();
+        let mut links = Vec::new();
+        let mut edge_data = Vec::new();
+        let mut nodes = Vec::new();
+
+        //dropped and folded links shrink their node, so the kept links have to be counted before any offset is known.
+        let mut lengths = Vec::with_capacity(self.nodes.len());
+        let mut scratch = BufReader::new(File::open(&self.scratch_path)?);
+        for node in 0..self.nodes.len() {
+            let template_links =
+                read_links(&mut scratch, &mut links, &mut edge_data, self.has_edge_data)?;
+            let mut first_template_link = links.len() - template_links;
+            nodes.clear();
+            for (index, &target) in links.iter().enumerate() {
+                match resolved[target as usize] {
+                    NO_NODE if dangling_links == DanglingLinks::Drop => {
+                        if index < first_template_link {
+                            first_template_link -= 1;
+                        }
+                    }
+                    target_node => nodes.push((target_node, None)),
+                }
+            }
+            fold_resolved_links(
+                node as u32,
+                NO_NODE,
+                &mut nodes,
+                first_template_link,
+                folding,
+                &mut RemovedLinks::default(),
+            );
+            lengths.push(nodes.len());
+        }
+        drop(scratch);
+        let link_size = graph_builder.link_size();
+        let mut offsets = Vec::with_capacity(self.nodes.len());
+        let mut offset = FILE_HEADER_SIZE;
+        for num_links in lengths.iter() {
+            offsets.push(offset as i64);
+            offset += NODE_HEADER_SIZE + num_links * link_size;
+        }
+
+        let bar = progress_bar(self.nodes.len() as u64);
+        graph_builder.set_count(self.count());
+        graph_builder.write_file_header()?;
+        let mut scratch = BufReader::new(File::open(&self.scratch_path)?);
+        let mut dangling: HashMap<u32, usize> = HashMap::new();
+        for (node, &title) in self.nodes.iter().enumerate() {
+            let template_links =
+                read_links(&mut scratch, &mut links, &mut edge_data, self.has_edge_data)?;
+            let mut first_template_link = links.len() - template_links;
+            nodes.clear();
+            for (index, &target) in links.iter().enumerate() {
+                let target_node = match resolved[target as usize] {
+                    NO_NODE => {
+                        *dangling.entry(target).or_insert(0) += 1;
+                        match dangling_links {
+                            DanglingLinks::Drop => {
+                                if index < first_template_link {
+                                    first_template_link -= 1;
+                                }
+                                continue;
+                            }
+                            DanglingLinks::Sentinel => NO_NODE,
+                            DanglingLinks::Fail => {
+                                return Err(WikigraphError::Consistency(format!(
+                                    "\"{}\" links to \"{}\", which is not a page or a redirect to one",
+                                    self.titles.title(title),
+                                    self.titles.title(target)
+                                )))
+                            }
+                        }
+                    }
+                    target_node => target_node,
+                };
+                nodes.push((target_node, edge_data.get(index).copied()));
+            }
+            let template_targets = fold_resolved_links(
+                node as u32,
+                NO_NODE,
+                &mut nodes,
+                first_template_link,
+                folding,
+                removed_links,
+            );
+            let current_position = graph_builder.get_current_position()?;
+            if current_position != offsets[node] as u64 {
+                return Err(WikigraphError::Consistency(format!(
+                    "\"{}\" should start at byte offset {} but the graph is at {}",
+                    self.titles.title(title),
+                    offsets[node],
+                    current_position
+                )));
+            }
+            graph_builder.write_node_header(nodes.len() as i32, template_targets as i32)?;
+            for &(target_node, edge_data) in nodes.iter() {
+                let byte_offset = match target_node {
+                    NO_NODE => DANGLING_LINK,
+                    target_node => offsets[target_node as usize],
+                };
+                graph_builder.write_value(byte_offset)?;
+                if let Some(edge_data) = edge_data {
+                    graph_builder.write_edge_data(edge_data)?;
+                }
+            }
+            bar.inc(1);
+        }
+        bar.finish();
+        graph_builder.flush_writer()?;
+        drop(scratch);
+        std::fs::remove_file(&self.scratch_path)?;
+
+        let redirect_entries: Vec<RedirectEntry> = self
+            .redirects
+            .iter()
+            .map(|(&from, &to)| RedirectEntry {
+                redirect_from: self.titles.title(from).to_owned(),
+                redirect_to: self.titles.title(to).to_owned(),
+            })
+            .collect();
+        for chunk in redirect_entries.chunks(INSERT_BATCH_SIZE) {
+            database_handler.add_redirect_entries(chunk)?;
+        }
+        let mut lookup_entries = Vec::with_capacity(INSERT_BATCH_SIZE);
+        for (node, &title) in self.nodes.iter().enumerate() {
+            lookup_entries.push(LookupEntry {
+                title: self.titles.title(title).to_owned(),
+                byteoffset: offsets[node],
+                length: (NODE_HEADER_SIZE + lengths[node] * link_size) as i32,
+            });
+            if lookup_entries.len() == INSERT_BATCH_SIZE {
+                database_handler.add_lookup_entries(&lookup_entries)?;
+                lookup_entries.clear();
+            }
+        }
+        database_handler.add_lookup_entries(&lookup_entries)?;
+
+        println!(
+            "{} links point at {} missing pages",
+            dangling.values().sum::<usize>(),
+            dangling.len()
+        );
+        if let Some(path) = dangling_report_path {
+            let dangling = dangling
+                .into_iter()
+                .map(|(target, count)| (self.titles.title(target).to_owned(), count))
+                .collect();
+            write_dangling_report(path, dangling)?;
+        }
+        Ok(())
+    }
+}
+
+//Reads the links of the next page of the scratch file into `links`, and their edge data into `edge_data` if there is
+//some. Returns how many of the links come from templates.
+fn read_links<R: Read>(
+    scratch: &mut R,
+    links: &mut Vec<u32>,
+    edge_data: &mut Vec<EdgeData>,
+    has_edge_data: bool,
+) -> Result<usize, WikigraphError> {
+    let num_links = scratch.read_u32::<LittleEndian>()?;
+    let template_links = scratch.read_u32::<LittleEndian>()?;
+    links.clear();
+    for _ in 0..num_links {
+        links.push(scratch.read_u32::<LittleEndian>()?);
+    }
+    edge_data.clear();
+    if has_edge_data {
+        for _ in 0..num_links {
+            let occurrences = scratch.read_u32::<LittleEndian>()?;
+            let flags = scratch.read_u32::<LittleEndian>()?;
+            edge_data.push(EdgeData::from_flags(occurrences, flags));
+        }
+    }
+    Ok(template_links as usize)
+}
@@ -1,20 +1,132 @@
-pub fn sanitize_string(input: &str) -> String {
-    // Trim leading and trailing whitespace
-    let trimmed = input.trim();
+//Namespaces of an English wiki with their keys, in the casing MediaWiki uses for them.
+pub(crate) const NAMESPACES: [(i32, &str); 25] = [
+    (-2, "Media"),
+    (-1, "Special"),
+    (1, "Talk"),
+    (2, "User"),
+    (3, "User talk"),
+    (4, "Wikipedia"),
+    (5, "Wikipedia talk"),
+    (6, "File"),
+    (7, "File talk"),
+    (8, "MediaWiki"),
+    (9, "MediaWiki talk"),
+    (10, "Template"),
+    (11, "Template talk"),
+    (12, "Help"),
+    (13, "Help talk"),
+    (14, "Category"),
+    (15, "Category talk"),
+    (100, "Portal"),
+    (101, "Portal talk"),
+    (118, "Draft"),
+    (119, "Draft talk"),
+    (710, "TimedText"),
+    (711, "TimedText talk"),
+    (828, "Module"),
+    (829, "Module talk"),
+];
 
-    // Replace all underscores with spaces and new lines with spaces
-    let mut text = trimmed.replace('_', " ");
-    text = text.replace("\r\n", " ");
-    text = text.replace('\n', " ");
+//Other names MediaWiki accepts for the namespaces above.
+pub(crate) const NAMESPACE_ALIASES: [(&str, &str); 6] = [
+    ("WP", "Wikipedia"),
+    ("WT", "Wikipedia talk"),
+    ("Project", "Wikipedia"),
+    ("Project talk", "Wikipedia talk"),
+    ("Image", "File"),
+    ("Image talk", "File talk"),
+];
 
-    // Convert to lowercase
-    let lowercase = text.to_lowercase();
+//Normalizes a page title the way MediaWiki does, so it can be used as the key of the lookup table.
+//Underscores become spaces, runs of whitespace are collapsed, the namespace prefix (if any) gets its
+//canonical spelling and only the first letter of the title is uppercased. "ALGOL" and "Algol" stay different pages.
+pub fn normalize_title(input: &str) -> String {
+    // Replace all underscores with spaces and collapse whitespace (including new lines)
+    let spaced = input.replace('_', " ");
+    let collapsed = spaced.split_whitespace().collect::<Vec<_>>().join(" ");
 
     // Remove colon if the first character that is not a whitespace is a colon
+    let title = match collapsed.strip_prefix(':') {
+        Some(stripped) => stripped.trim_start(),
+        None => collapsed.as_str(),
+    };
 
-    if lowercase.starts_with(':') {
-        lowercase.strip_prefix(':').unwrap().to_string()
-    } else {
-        lowercase
+    if let Some((prefix, rest)) = title.split_once(':') {
+        if let Some(namespace) = canonical_namespace(prefix.trim_end()) {
+            let rest = rest.trim_start();
+            if rest.is_empty() {
+                return format!("{}:", namespace);
+            }
+            return format!("{}:{}", namespace, uppercase_first(rest));
+        }
+    }
+    uppercase_first(title)
+}
+
+fn canonical_namespace(prefix: &str) -> Option<&'static str> {
+    NAMESPACES
+        .iter()
+        .find(|(_, namespace)| namespace.eq_ignore_ascii_case(prefix))
+        .map(|(_, namespace)| *namespace)
+        .or_else(|| {
+            NAMESPACE_ALIASES
+                .iter()
+                .find(|(alias, _)| alias.eq_ignore_ascii_case(prefix))
+                .map(|(_, namespace)| *namespace)
+        })
+}
+
+//Characters without a single character uppercase form (ie: ß -> SS) are kept as is, like MediaWiki does.
+fn uppercase_first(input: &str) -> String {
+    let mut chars = input.chars();
+    match chars.next() {
+        Some(first) => {
+            let mut upper = first.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(upper), None) => std::iter::once(upper).chain(chars).collect(),
+                _ => input.to_owned(),
+            }
+        }
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_title;
+
+    #[test]
+    fn keeps_titles_that_only_differ_by_case_apart() {
+        assert_eq!(normalize_title("ALGOL"), "ALGOL");
+        assert_eq!(normalize_title("Algol"), "Algol");
+        assert_eq!(normalize_title("algol"), "Algol");
+    }
+
+    #[test]
+    fn turns_underscores_into_spaces_and_collapses_whitespace() {
+        assert_eq!(normalize_title("foo_bar  baz"), "Foo bar baz");
+        assert_eq!(normalize_title(" foo\n bar_ "), "Foo bar");
+    }
+
+    #[test]
+    fn canonicalizes_namespace_prefixes() {
+        assert_eq!(normalize_title("category:x"), "Category:X");
+        assert_eq!(normalize_title("CATEGORY : x"), "Category:X");
+        assert_eq!(normalize_title("wp:About"), "Wikipedia:About");
+        assert_eq!(normalize_title("image:Foo.png"), "File:Foo.png");
+        //not a namespace, only the first letter changes
+        assert_eq!(normalize_title("star wars: episode"), "Star wars: episode");
+    }
+
+    #[test]
+    fn keeps_first_letters_without_a_single_uppercase_form() {
+        assert_eq!(normalize_title("ßtraße"), "ßtraße");
+        assert_eq!(normalize_title("ébène"), "Ébène");
+    }
+
+    #[test]
+    fn strips_a_leading_colon() {
+        assert_eq!(normalize_title(":Category:x"), "Category:X");
+        assert_eq!(normalize_title(": foo"), "Foo");
     }
 }
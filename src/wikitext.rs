@@ -0,0 +1,658 @@
+use std::mem;
+
+//Tags whose contents are not wikitext, nothing inside them is a link.
+const RAW_TAGS: [&str; 17] = [
+    "nowiki",
+    "pre",
+    "math",
+    "chem",
+    "ce",
+    "syntaxhighlight",
+    "source",
+    "score",
+    "timeline",
+    "hiero",
+    "templatedata",
+    "graph",
+    "mapframe",
+    "maplink",
+    "inputbox",
+    "categorytree",
+    "includeonly",
+];
+
+//Tags whose contents are wikitext of their own: braces and brackets inside them can not close anything outside.
+const CONTAINER_TAGS: [&str; 5] = ["ref", "references", "gallery", "poem", "indicator"];
+
+//Html and other tags that are dropped, their contents are parsed like the text around them.
+const MARKUP_TAGS: [&str; 58] = [
+    "abbr",
+    "b",
+    "bdi",
+    "bdo",
+    "big",
+    "blockquote",
+    "br",
+    "caption",
+    "center",
+    "cite",
+    "code",
+    "data",
+    "dd",
+    "del",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "font",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "noinclude",
+    "ol",
+    "onlyinclude",
+    "p",
+    "q",
+    "rb",
+    "rp",
+    "rt",
+    "ruby",
+    "s",
+    "samp",
+    "section",
+    "small",
+    "span",
+    "strike",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "td",
+    "templatestyles",
+    "th",
+    "tr",
+    "tt",
+    "u",
+    "ul",
+];
+
+//The pieces of wikitext that decide where links are, everything else is Text. Comments and html tags are dropped.
+//Runs of braces are left for `parse` to pair up, `{{{{` can only be split once its closing braces are known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    Text(&'a str),
+    //Contents of a <nowiki>, <pre>, <math>... which are not parsed any further.
+    Raw(&'a str),
+    LinkOpen,
+    LinkClose,
+    OpenBraces(usize),
+    CloseBraces(usize),
+    //`{|` at the start of a line.
+    TableOpen,
+    //`|}` at the start of a line, with the number of `}` in the run.
+    TableClose(usize),
+    Pipe,
+    //Tags from CONTAINER_TAGS, with their name as written.
+    TagOpen(&'a str),
+    TagClose(&'a str),
+}
+
+enum Step<'a> {
+    Token(Token<'a>),
+    //Markup that does not end up in the output, ie: a comment.
+    Skip,
+    //Not markup after all, the character is part of the text.
+    Plain,
+}
+
+//Yields the tokens of a page's wikitext with the byte offset they start at.
+pub struct Tokenizer<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Tokenizer { text, pos: 0 }
+    }
+
+    fn run_of(&self, start: usize, byte: u8) -> usize {
+        self.text.as_bytes()[start..]
+            .iter()
+            .take_while(|&&b| b == byte)
+            .count()
+    }
+
+    fn at_line_start(&self, pos: usize) -> bool {
+        let before = self.text.as_bytes()[..pos]
+            .iter()
+            .rev()
+            .find(|&&b| b != b' ' && b != b'\t');
+        matches!(before, None | Some(b'\n'))
+    }
+
+    fn text_end(&self, from: usize) -> usize {
+        self.text.as_bytes()[from..]
+            .iter()
+            .position(|b| matches!(b, b'[' | b']' | b'{' | b'}' | b'|' | b'<'))
+            .map_or(self.text.len(), |end| from + end)
+    }
+
+    fn markup(&mut self, start: usize) -> Step<'a> {
+        let bytes = self.text.as_bytes();
+        let consume = |tokenizer: &mut Self, len: usize, token: Token<'a>| {
+            tokenizer.pos = start + len;
+            Step::Token(token)
+        };
+        match bytes[start] {
+            //`[[[` is a `[` followed by a link.
+            b'[' if self.run_of(start, b'[') == 2 => consume(self, 2, Token::LinkOpen),
+            b']' if self.run_of(start, b']') >= 2 => consume(self, 2, Token::LinkClose),
+            b'{' => match self.run_of(start, b'{') {
+                1 if bytes.get(start + 1) == Some(&b'|') && self.at_line_start(start) => {
+                    consume(self, 2, Token::TableOpen)
+                }
+                1 => Step::Plain,
+                run => consume(self, run, Token::OpenBraces(run)),
+            },
+            b'}' => match self.run_of(start, b'}') {
+                1 => Step::Plain,
+                run => consume(self, run, Token::CloseBraces(run)),
+            },
+            b'|' if bytes.get(start + 1) == Some(&b'}') && self.at_line_start(start) => {
+                let run = self.run_of(start + 1, b'}');
+                consume(self, run + 1, Token::TableClose(run))
+            }
+            b'|' => consume(self, 1, Token::Pipe),
+            b'<' => self.tag(start),
+            _ => Step::Plain,
+        }
+    }
+
+    fn tag(&mut self, start: usize) -> Step<'a> {
+        let rest = &self.text[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            //an unclosed comment runs to the end of the page, like in MediaWiki.
+            self.pos = comment
+                .find("-->")
+                .map_or(self.text.len(), |end| start + 4 + end + 3);
+            return Step::Skip;
+        }
+        let closing = rest.starts_with("</");
+        let name_start = start + if closing { 2 } else { 1 };
+        let name_len = self.text.as_bytes()[name_start..]
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric())
+            .count();
+        let name = &self.text[name_start..name_start + name_len];
+        let name_end = name_start + name_len;
+        //`a < b` and `x<y` are text.
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !matches!(
+                self.text.as_bytes().get(name_end),
+                Some(b' ' | b'\t' | b'\n' | b'/' | b'>')
+            )
+        {
+            return Step::Plain;
+        }
+        let Some(tag_end) = self.text[name_end..]
+            .find('>')
+            .map(|end| name_end + end + 1)
+        else {
+            return Step::Plain;
+        };
+        let self_closing = self.text[..tag_end - 1].ends_with('/');
+
+        if is_one_of(name, &RAW_TAGS) {
+            self.pos = tag_end;
+            if closing || self_closing {
+                return Step::Skip;
+            }
+            //without its closing tag the opening one is ignored and the rest is parsed as usual.
+            return match find_closing_tag(self.text, tag_end, name) {
+                Some((content_end, close_end)) => {
+                    self.pos = close_end;
+                    Step::Token(Token::Raw(&self.text[tag_end..content_end]))
+                }
+                None => Step::Skip,
+            };
+        }
+        if is_one_of(name, &CONTAINER_TAGS) {
+            self.pos = tag_end;
+            return match (closing, self_closing) {
+                (true, _) => Step::Token(Token::TagClose(name)),
+                (false, true) => Step::Skip,
+                (false, false) => Step::Token(Token::TagOpen(name)),
+            };
+        }
+        if is_one_of(name, &MARKUP_TAGS) {
+            self.pos = tag_end;
+            return Step::Skip;
+        }
+        Step::Plain
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = (usize, Token<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.pos;
+            if start >= self.text.len() {
+                return None;
+            }
+            match self.markup(start) {
+                Step::Token(token) => return Some((start, token)),
+                Step::Skip => continue,
+                Step::Plain => {
+                    self.pos = self.text_end(start + 1);
+                    return Some((start, Token::Text(&self.text[start..self.pos])));
+                }
+            }
+        }
+    }
+}
+
+fn is_one_of(name: &str, tags: &[&str]) -> bool {
+    tags.iter().any(|tag| tag.eq_ignore_ascii_case(name))
+}
+
+//Start of `</name>` after `from` and the end of it, the name is matched ignoring case.
+fn find_closing_tag(text: &str, from: usize, name: &str) -> Option<(usize, usize)> {
+    text[from..].match_indices("</").find_map(|(index, _)| {
+        let name_start = from + index + 2;
+        let candidate = text.get(name_start..name_start + name.len())?;
+        if !candidate.eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let after = &text[name_start + name.len()..];
+        let trimmed = after.trim_start();
+        trimmed
+            .starts_with('>')
+            .then(|| (from + index, text.len() - trimmed.len() + 1))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind<'a> {
+    //[[target|text]]
+    Link,
+    //{{name|parameter|...}}
+    Template,
+    //{{{name|default}}}, only found on templates themselves but sometimes copied into articles.
+    Parameter,
+    //{| ... |}
+    Table,
+    //<ref>...</ref> and the other CONTAINER_TAGS, with the name as written.
+    Tag(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element<'a> {
+    pub kind: Kind<'a>,
+    //Byte offset of the opening markup in the page's text.
+    pub start: usize,
+    pub children: Vec<Node<'a>>,
+}
+
+impl<'a> Element<'a> {
+    //Children split at the pipes of this element: the target and text of a link, the name and parameters of a template.
+    pub fn parts(&self) -> impl Iterator<Item = &[Node<'a>]> {
+        self.children.split(|node| matches!(node, Node::Pipe))
+    }
+
+    //Name of a template, None if it is not plain text (ie: {{ {{name}} }}).
+    pub fn template_name(&self) -> Option<String> {
+        plain_text(self.parts().next()?).map(|name| name.trim().to_owned())
+    }
+}
+
+//Text of `nodes`, None if there is anything else in them.
+pub fn plain_text(nodes: &[Node]) -> Option<String> {
+    let mut text = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(part) => text.push_str(part),
+            _ => return None,
+        }
+    }
+    Some(text)
+}
+
+//Name of a `name=value` template parameter, None if it is unnamed.
+pub fn parameter_name(parameter: &[Node]) -> Option<String> {
+    let mut name = String::new();
+    for node in parameter {
+        if let Node::Text(text) = node {
+            if let Some((end, _)) = text.split_once('=') {
+                name.push_str(end);
+                return Some(name.trim().to_owned());
+            }
+            name.push_str(text);
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node<'a> {
+    Text(&'a str),
+    Raw(&'a str),
+    //Separates the target of a link from its text, and the parameters of a template.
+    Pipe,
+    Element(Element<'a>),
+}
+
+enum Open<'a> {
+    Link,
+    //Braces of a run that are not paired up yet.
+    Braces(usize),
+    Table,
+    Tag(&'a str),
+}
+
+struct Frame<'a> {
+    open: Open<'a>,
+    start: usize,
+    children: Vec<Node<'a>>,
+}
+
+//Pairs up the tokens into a tree. Closing markup only closes the innermost construct, the way MediaWiki's
+//preprocessor does it, except that an unclosed link is given up on when the construct around it is closed.
+//Anything left open at the end of the page is text.
+struct TreeBuilder<'a> {
+    text: &'a str,
+    root: Vec<Node<'a>>,
+    stack: Vec<Frame<'a>>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn push(&mut self, node: Node<'a>) {
+        match self.stack.last_mut() {
+            Some(frame) => frame.children.push(node),
+            None => self.root.push(node),
+        }
+    }
+
+    fn open(&mut self, open: Open<'a>, start: usize) {
+        self.stack.push(Frame {
+            open,
+            start,
+            children: Vec::new(),
+        });
+    }
+
+    //Turns the innermost frame back into text: its opening markup followed by what was parsed inside it.
+    fn break_frame(&mut self) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+        let opener = match frame.open {
+            Open::Link | Open::Table => Some(&self.text[frame.start..frame.start + 2]),
+            Open::Braces(0) | Open::Tag(_) => None,
+            Open::Braces(count) => Some(&self.text[frame.start..frame.start + count]),
+        };
+        if let Some(opener) = opener {
+            self.push(Node::Text(opener));
+        }
+        for child in frame.children {
+            self.push(child);
+        }
+    }
+
+    fn close_frame(&mut self, kind: Kind<'a>) {
+        let frame = self.stack.pop().unwrap();
+        self.push(Node::Element(Element {
+            kind,
+            start: frame.start,
+            children: frame.children,
+        }));
+    }
+
+    //Gives up on the links opened inside the innermost other frame if `matches` accepts that frame.
+    fn unwind_links(&mut self, matches: impl Fn(&Open) -> bool) -> bool {
+        let Some(index) = self
+            .stack
+            .iter()
+            .rposition(|frame| !matches!(frame.open, Open::Link))
+        else {
+            return false;
+        };
+        if !matches(&self.stack[index].open) {
+            return false;
+        }
+        while self.stack.len() > index + 1 {
+            self.break_frame();
+        }
+        true
+    }
+
+    fn close_link(&mut self, start: usize) {
+        match self.stack.last() {
+            Some(Frame {
+                open: Open::Link, ..
+            }) => self.close_frame(Kind::Link),
+            _ => self.push(Node::Text(&self.text[start..start + 2])),
+        }
+    }
+
+    //`count` closing braces starting at `start`, they pair up with the innermost run of opening braces,
+    //three at a time for a parameter and two for a template.
+    fn close_braces(&mut self, start: usize, count: usize) {
+        let mut left = count;
+        while left >= 2 && self.unwind_links(|open| matches!(open, Open::Braces(_))) {
+            let frame = self.stack.last_mut().unwrap();
+            let Open::Braces(open) = frame.open else {
+                unreachable!()
+            };
+            let (used, kind) = match open.min(left) {
+                2 => (2, Kind::Template),
+                _ => (3, Kind::Parameter),
+            };
+            left -= used;
+            //the braces closest to the text are the ones that are paired up.
+            let element = Node::Element(Element {
+                kind,
+                start: frame.start + open - used,
+                children: mem::take(&mut frame.children),
+            });
+            if open - used >= 2 {
+                frame.open = Open::Braces(open - used);
+                frame.children.push(element);
+            } else {
+                frame.open = Open::Braces(open - used);
+                self.break_frame();
+                self.push(element);
+            }
+        }
+        if left > 0 {
+            let end = start + count;
+            self.push(Node::Text(&self.text[end - left..end]));
+        }
+    }
+
+    fn close_table(&mut self, start: usize, braces: usize) {
+        if self.unwind_links(|open| matches!(open, Open::Table)) {
+            self.close_frame(Kind::Table);
+            if braces > 1 {
+                self.close_braces(start + 2, braces - 1);
+            }
+        } else {
+            self.push(Node::Pipe);
+            self.close_braces(start + 1, braces);
+        }
+    }
+
+    fn close_tag(&mut self, name: &str) {
+        let Some(index) = self.stack.iter().rposition(
+            |frame| matches!(frame.open, Open::Tag(open) if open.eq_ignore_ascii_case(name)),
+        ) else {
+            return;
+        };
+        while self.stack.len() > index + 1 {
+            self.break_frame();
+        }
+        let Open::Tag(name) = self.stack[index].open else {
+            unreachable!()
+        };
+        self.close_frame(Kind::Tag(name));
+    }
+
+    fn finish(mut self) -> Vec<Node<'a>> {
+        while !self.stack.is_empty() {
+            self.break_frame();
+        }
+        self.root
+    }
+}
+
+//Parses the wikitext of a page into a tree of links, templates, tables and tags.
+pub fn parse(text: &str) -> Vec<Node<'_>> {
+    let mut tree = TreeBuilder {
+        text,
+        root: Vec::new(),
+        stack: Vec::new(),
+    };
+    for (start, token) in Tokenizer::new(text) {
+        match token {
+            Token::Text(text) => tree.push(Node::Text(text)),
+            Token::Raw(text) => tree.push(Node::Raw(text)),
+            Token::Pipe => tree.push(Node::Pipe),
+            Token::LinkOpen => tree.open(Open::Link, start),
+            Token::LinkClose => tree.close_link(start),
+            Token::OpenBraces(count) => tree.open(Open::Braces(count), start),
+            Token::CloseBraces(count) => tree.close_braces(start, count),
+            Token::TableOpen => tree.open(Open::Table, start),
+            Token::TableClose(braces) => tree.close_table(start, braces),
+            Token::TagOpen(name) => tree.open(Open::Tag(name), start),
+            Token::TagClose(name) => tree.close_tag(name),
+        }
+    }
+    tree.finish()
+}
+
+//Length of the lead section: the text before the first `== heading ==` line, all of it if there is none.
+//Headings are found line by line, one inside a comment or a <nowiki> still counts.
+pub fn lead_section_end(text: &str) -> usize {
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        let heading = line.trim_end();
+        if heading.len() > 2 && heading.starts_with('=') && heading.ends_with('=') {
+            return start;
+        }
+        start += line.len();
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, plain_text, Kind, Node};
+    use crate::link_handler::{LinkHandler, WikitextLinkHandler};
+
+    fn targets(text: &str) -> Vec<String> {
+        WikitextLinkHandler::new(Vec::new())
+            .extract_links(text.to_owned())
+            .into_iter()
+            .map(|link| link.target)
+            .collect()
+    }
+
+    #[test]
+    fn skips_nested_templates_as_a_whole() {
+        assert_eq!(
+            targets("{{Infobox|x={{cite|[[Hidden]]}}|y=[[Leaked]]}} [[After]]"),
+            ["After"]
+        );
+        let nodes = parse("{{Infobox|x={{cite}}}} [[After]]");
+        match &nodes[0] {
+            Node::Element(template) => {
+                assert_eq!(template.kind, Kind::Template);
+                assert_eq!(template.template_name().as_deref(), Some("Infobox"));
+            }
+            node => panic!("expected a template, got {:?}", node),
+        }
+    }
+
+    #[test]
+    fn a_less_than_sign_is_text() {
+        assert_eq!(targets("a < b [[X]] and c<d [[Y]]"), ["X", "Y"]);
+    }
+
+    #[test]
+    fn keeps_links_in_refs_but_not_in_raw_tags() {
+        assert_eq!(
+            targets("a<ref name=x>see [[Cited]]</ref> <ref name=y /> [[After]]"),
+            ["Cited", "After"]
+        );
+        assert_eq!(
+            targets("<nowiki>[[A]]</nowiki> <math>[[B]]</math> <pre>[[C]]</pre> [[D]]"),
+            ["D"]
+        );
+        assert_eq!(targets("<NoWiki>[[A]]</nowiki> [[B]]"), ["B"]);
+    }
+
+    #[test]
+    fn skips_comments() {
+        assert_eq!(targets("<!-- [[Y]] --> [[Z]]"), ["Z"]);
+        assert_eq!(targets("[[Z]] <!-- [[Y]] unclosed"), ["Z"]);
+    }
+
+    #[test]
+    fn keeps_links_in_tables() {
+        let text = "{| class=\"wikitable\"\n|-\n| [[Cell]] || {{flag|X}}\n|}\n[[After]]";
+        assert_eq!(targets(text), ["Cell", "After"]);
+        match &parse(text)[0] {
+            Node::Element(table) => assert_eq!(table.kind, Kind::Table),
+            node => panic!("expected a table, got {:?}", node),
+        }
+    }
+
+    #[test]
+    fn unclosed_markup_at_the_end_is_text() {
+        assert_eq!(targets("[[A]] {{Unclosed|[[B]]"), ["A", "B"]);
+        assert_eq!(targets("[[A]] [[Unclosed"), ["A"]);
+        let nodes = parse("x {{y");
+        assert_eq!(plain_text(&nodes).as_deref(), Some("x {{y"));
+        let nodes = parse("x [[y");
+        assert_eq!(plain_text(&nodes).as_deref(), Some("x [[y"));
+    }
+
+    #[test]
+    fn keeps_links_in_file_captions() {
+        assert_eq!(
+            targets("[[File:Foo.png|thumb|A [[Caption link]]]] [[After]]"),
+            ["File:Foo.png", "Caption link", "After"]
+        );
+    }
+
+    #[test]
+    fn records_the_leading_colon() {
+        let links = WikitextLinkHandler::new(Vec::new())
+            .extract_links("[[Category:X]] [[:Category:Y]] [[ :File:Z.png]] [[A]]".to_owned());
+        let colons: Vec<_> = links
+            .iter()
+            .map(|link| (link.target.as_str(), link.leading_colon))
+            .collect();
+        assert_eq!(
+            colons,
+            [
+                ("Category:X", false),
+                ("Category:Y", true),
+                ("File:Z.png", true),
+                ("A", false)
+            ]
+        );
+    }
+}